@@ -1,40 +1,486 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{self, BufReader, Read, Stdin};
-use std::path::PathBuf;
-use std::process::ChildStdout;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Stdin, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, ExitStatus};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::debug;
+use log::{debug, error, info, warn};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 
-use crate::output::{Output, OutputData, TaskId, BUFSIZE};
-use crate::plugin::OutputPath;
-use crate::pre_process::{PreProcessedInput, PreProcessor};
+use crate::output::{LineCount, Output, OutputContext, OutputData, SkipReason, TaskId, BUFSIZE};
+use crate::plugin::{self, FileType, OutputPath, Plugin};
+use crate::pre_process::{read_head, PreProcessedInput, PreProcessor};
+use crate::rate::{RateLimiter, ThrottleAction};
 use crate::walk;
 
+static SELFTEST_INPUT: &[u8] = b"selftest\n";
+
 pub struct InputFactory {
     pub last_id: AtomicU64,
+    pub seen: Option<Mutex<HashSet<Vec<u8>>>>,
+    pub budget: Arc<OutputBudget>,
+    pub spawn_limiter: Option<RateLimiter>,
+    // A task that waits longer than this for the spawn limiter is throttled
+    pub throttle_wait: Option<Duration>,
+    pub throttle_action: ThrottleAction,
+    pub stats: Stats,
+    // Tasks finished so far, only counted when set
+    pub progress: Option<Arc<AtomicUsize>>,
+    pub container_delimiter: Option<String>,
+    // Only the first this many bytes of the task data are hashed for container records
+    pub hash_bytes: Option<u64>,
+    // Leaves the temp files of tasks for inspection, they are only logged
+    pub keep_temp: bool,
 }
 
 impl InputFactory {
-    pub fn new() -> InputFactory {
+    pub fn new(dedup: bool) -> InputFactory {
         InputFactory {
             last_id: AtomicU64::new(0),
+            seen: if dedup {
+                Some(Mutex::new(HashSet::new()))
+            } else {
+                None
+            },
+            budget: Arc::default(),
+            spawn_limiter: None,
+            throttle_wait: None,
+            throttle_action: ThrottleAction::Wait,
+            stats: Stats::default(),
+            progress: None,
+            container_delimiter: None,
+            hash_bytes: None,
+            keep_temp: false,
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if self.keep_temp {
+            info!("Keeping temp file {:?}", path);
+            Ok(())
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.keep_temp {
+            info!("Keeping temp dir {:?}", path);
+            Ok(())
+        } else {
+            fs::remove_dir_all(path)
+        }
+    }
+
+    // The item_path of a file unpacked from a container
+    pub fn nested_path(&self, container: &Path, path: PathBuf) -> PathBuf {
+        match &self.container_delimiter {
+            Some(delimiter) if !container.as_os_str().is_empty() => {
+                let mut nested = container.as_os_str().to_owned();
+                nested.push(delimiter);
+                nested.push(path);
+                nested.into()
+            }
+            _ => container.join(path),
+        }
+    }
+
+    pub fn check_duplicate(&self, path: &Path) -> io::Result<Option<String>> {
+        match &self.seen {
+            Some(seen) => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut File::open(path)?, &mut hasher)?;
+                let digest = hasher.finalize();
+                if seen.lock().unwrap().insert(digest.to_vec()) {
+                    Ok(None)
+                } else {
+                    Ok(Some(format!("{:x}", digest)))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn count_progress(&self) {
+        if let Some(progress) = &self.progress {
+            progress.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    pub fn new_input<P: Into<PathBuf>>(&self, item_path: P, data: InputData) -> Input {
+    pub fn new_task_id(&self) -> TaskId {
+        TaskId::new(self.last_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn new_input<P: Into<PathBuf>>(
+        &self,
+        item_path: P,
+        origin: PathBuf,
+        data: InputData,
+    ) -> Input {
+        self.budget.enter(&origin);
         Input {
-            task_id: TaskId::new(self.last_id.fetch_add(1, Ordering::Relaxed)),
+            task_id: self.new_task_id(),
             item_path: item_path.into(),
+            origin,
+            parent_type: None,
             data,
         }
     }
+
+    pub fn new_child_input<P: Into<PathBuf>, R>(
+        &self,
+        item_path: P,
+        parent: &PreProcessedInput<R>,
+        data: InputData,
+    ) -> Input {
+        let mut input = self.new_input(item_path, parent.origin.clone(), data);
+        input.parent_type = Some(parent.item_type.clone());
+        input
+    }
+}
+
+// Caps the bytes unpacked from a single top-level input, guards against decompression bombs
+#[derive(Debug, Default)]
+pub struct OutputBudget {
+    pub max_bytes: Option<u64>,
+    // Relative to the size of the top-level input file
+    pub max_ratio: Option<u64>,
+    used: Mutex<HashMap<PathBuf, OriginUsage>>,
+}
+
+#[derive(Debug, Default)]
+struct OriginUsage {
+    bytes: u64,
+    // Inputs of the origin not yet finished
+    inputs: u64,
+    // Read once when the origin is entered, None when it isn't a file
+    size: Option<u64>,
+}
+
+impl OutputBudget {
+    pub fn new(max_bytes: Option<u64>, max_ratio: Option<u64>) -> OutputBudget {
+        OutputBudget {
+            max_bytes,
+            max_ratio,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_ratio.is_some()
+    }
+
+    // Counts an input of the origin, its total is kept until the last input finishes
+    pub fn enter(&self, origin: &Path) {
+        if !self.enabled() {
+            return;
+        }
+        if let Some(usage) = self.used.lock().unwrap().get_mut(origin) {
+            usage.inputs += 1;
+            return;
+        }
+        // Looked up outside the lock, it's shared by all the workers
+        let size = self.max_ratio.and_then(|_| {
+            let size = fs::metadata(origin)
+                .ok()
+                .filter(|x| x.is_file())
+                .map(|x| x.len());
+            if size.is_none() {
+                warn!(
+                    "No size for {:?}, --max-unpacked-ratio doesn't apply to it",
+                    origin
+                );
+            }
+            size
+        });
+        let mut used = self.used.lock().unwrap();
+        let usage = used.entry(origin.to_path_buf()).or_insert(OriginUsage {
+            size,
+            ..Default::default()
+        });
+        usage.inputs += 1;
+    }
+
+    pub fn finish(&self, origin: &Path) {
+        if self.enabled() {
+            let mut used = self.used.lock().unwrap();
+            if let Some(usage) = used.get_mut(origin) {
+                usage.inputs = usage.inputs.saturating_sub(1);
+                if usage.inputs == 0 {
+                    used.remove(origin);
+                }
+            }
+        }
+    }
+
+    // Adds the bytes to the total of the origin, returns the total when it's over budget
+    pub fn charge(&self, origin: &Path, bytes: u64) -> Option<u64> {
+        if !self.enabled() {
+            return None;
+        }
+        let mut used = self.used.lock().unwrap();
+        let usage = used.entry(origin.to_path_buf()).or_default();
+        usage.bytes += bytes;
+        let total = usage.bytes;
+        let over_bytes = self.max_bytes.is_some_and(|max| total > max);
+        let over_ratio = match (self.max_ratio, usage.size) {
+            (Some(ratio), Some(size)) => total > size.saturating_mul(ratio),
+            _ => false,
+        };
+        if over_bytes || over_ratio {
+            Some(total)
+        } else {
+            None
+        }
+    }
+}
+
+// The stdout of an unpacker, charged to the budget of its origin as it's read. Once over
+// budget the pipe is closed, which stops the unpacker, and reads fail.
+#[derive(Debug)]
+pub struct BudgetReader {
+    stdout: Option<ChildStdout>,
+    budget: Arc<OutputBudget>,
+    origin: PathBuf,
+    // The total of the origin when it went over budget, 0 until then
+    exceeded: Arc<AtomicU64>,
+    // The process writing the stdout
+    writer: KillHandle,
+}
+
+impl BudgetReader {
+    fn new(
+        stdout: ChildStdout,
+        budget: Arc<OutputBudget>,
+        origin: PathBuf,
+        writer: KillHandle,
+    ) -> BudgetReader {
+        BudgetReader {
+            stdout: Some(stdout),
+            budget,
+            origin,
+            exceeded: Arc::default(),
+            writer,
+        }
+    }
+}
+
+impl Read for BudgetReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stdout = match &mut self.stdout {
+            Some(stdout) => stdout,
+            None => return Err(io::Error::other("Unpacked output over budget")),
+        };
+        let n = stdout.read(buf)?;
+        if let Some(total) = self.budget.charge(&self.origin, n as u64) {
+            self.exceeded.store(total, Ordering::Relaxed);
+            self.stdout = None;
+            return Err(io::Error::other("Unpacked output over budget"));
+        }
+        Ok(n)
+    }
+}
+
+// Lets another thread kill a child until its task has waited on it. The pid is cleared
+// before the child is reaped, so a kill can't hit a reused pid.
+#[derive(Clone, Debug)]
+pub struct KillHandle(Arc<Mutex<Option<u32>>>);
+
+impl KillHandle {
+    fn new(child: &Child) -> KillHandle {
+        KillHandle(Arc::new(Mutex::new(Some(child.id()))))
+    }
+
+    #[cfg(unix)]
+    fn kill(&self) {
+        if let Some(pid) = *self.0.lock().unwrap() {
+            // Only signals the pid, the child is still reaped by its task
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn kill(&self) {}
+
+    // Returns the exit status and CPU time of the child
+    fn wait(&self, child: &mut Child) -> io::Result<(ExitStatus, Duration)> {
+        #[cfg(unix)]
+        wait_exited(child.id())?;
+        *self.0.lock().unwrap() = None;
+        plugin::wait_child(child)
+    }
+}
+
+// Waits for the child to exit, leaving it to be reaped
+#[cfg(unix)]
+fn wait_exited(pid: u32) -> io::Result<()> {
+    loop {
+        let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        let flags = libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+// Run-wide counters for the summary record
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub inputs: AtomicU64,
+    pub unknown: AtomicU64,
+    // Skipped by the empty input policy
+    pub empty: AtomicU64,
+    // Tasks that waited longer than the throttle wait for the spawn limiter
+    pub throttled: AtomicU64,
+    pub plugin_errors: AtomicU64,
+    pub types: Mutex<HashMap<FileType, u64>>,
+    pub plugins: Mutex<HashMap<String, Arc<PluginStats>>>,
+}
+
+// Counters of a single plugin, shared with the outputs of its tasks
+#[derive(Debug, Default)]
+pub struct PluginStats {
+    pub tasks: AtomicU64,
+    pub errors: AtomicU64,
+    // Of the data outputs of the plugin
+    pub bytes: AtomicU64,
+}
+
+impl Stats {
+    // Counts the type of a pre-processed input, inputs without a plugin count as unknown
+    // and their skipped record is written. Skipped empty inputs are counted apart.
+    fn count_type<R, O: Fn(Output)>(
+        &self,
+        ppi: Result<PreProcessedInput<R>, Option<Output>>,
+        output_cb: &O,
+    ) -> Option<PreProcessedInput<R>> {
+        match ppi {
+            Ok(x) => {
+                *self
+                    .types
+                    .lock()
+                    .unwrap()
+                    .entry(x.item_type.clone())
+                    .or_insert(0) += 1;
+                Some(x)
+            }
+            Err(None) => {
+                self.empty.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Err(Some(skipped)) => {
+                match skipped.data {
+                    OutputData::Skipped(SkipReason::Empty) => &self.empty,
+                    _ => &self.unknown,
+                }
+                .fetch_add(1, Ordering::Relaxed);
+                output_cb(skipped);
+                None
+            }
+        }
+    }
+
+    pub fn plugin(&self, name: &str) -> Arc<PluginStats> {
+        let mut plugins = self.plugins.lock().unwrap();
+        plugins.entry(name.to_string()).or_default().clone()
+    }
+
+    pub fn summary(&self, elapsed: Duration) -> Value {
+        let plugins = self
+            .plugins
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, x)| {
+                let counters = json!({
+                    "tasks": x.tasks.load(Ordering::Relaxed),
+                    "errors": x.errors.load(Ordering::Relaxed),
+                    "bytes": x.bytes.load(Ordering::Relaxed),
+                });
+                (name.clone(), counters)
+            })
+            .collect::<Map<_, _>>();
+        json!({
+            "inputs": self.inputs.load(Ordering::Relaxed),
+            "types": *self.types.lock().unwrap(),
+            "unknown": self.unknown.load(Ordering::Relaxed),
+            "empty": self.empty.load(Ordering::Relaxed),
+            "throttled": self.throttled.load(Ordering::Relaxed),
+            "plugin_errors": self.plugin_errors.load(Ordering::Relaxed),
+            "plugins": plugins,
+            "elapsed_ms": elapsed.as_millis() as u64,
+        })
+    }
+}
+
+// Updates a digest with the bytes read through it, so the data is hashed while
+// it's streamed to the plugin instead of in a separate pass
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes_read: u64,
+    limit: Option<u64>,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader::with_limit(inner, None)
+    }
+
+    // Only the first limit bytes are hashed, the rest is still read through
+    pub fn with_limit(inner: R, limit: Option<u64>) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            bytes_read: 0,
+            limit,
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn hashed_bytes(&self) -> u64 {
+        self.limit
+            .map_or(self.bytes_read, |x| x.min(self.bytes_read))
+    }
+
+    pub fn digest(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        let hashed = match self.limit {
+            Some(limit) => limit.saturating_sub(self.bytes_read).min(len as u64) as usize,
+            None => len,
+        };
+        self.hasher.update(&buf[..hashed]);
+        self.bytes_read += len as u64;
+        Ok(len)
+    }
 }
 
 #[derive(Debug)]
 pub struct Input {
     pub task_id: TaskId,
     pub item_path: PathBuf,
+    // The top-level file this input was extracted from
+    pub origin: PathBuf,
+    // Type of the input this one was unpacked from
+    pub parent_type: Option<FileType>,
     pub data: InputData,
 }
 
@@ -46,33 +492,162 @@ impl Input {
         input_cb: I,
         output_cb: O,
     ) -> io::Result<()> {
+        let exceeded = match &self.data {
+            InputData::Stdout(stdout) => stdout.exceeded.clone(),
+            _ => return self.handle_data(factory, pre_processor, input_cb, output_cb),
+        };
+        let (task_id, item_path, origin) =
+            (self.task_id, self.item_path.clone(), self.origin.clone());
+        let parent = self
+            .parent_type
+            .as_ref()
+            .and_then(|x| pre_processor.plugins.get(x))
+            .map(|x| x.name.clone())
+            .unwrap_or_default();
+        match self.handle_data(factory, pre_processor, input_cb, &output_cb) {
+            // Reported as a bomb of the unpacker, not as an error of this input
+            Err(_) if exceeded.load(Ordering::Relaxed) > 0 => {
+                let total = exceeded.load(Ordering::Relaxed);
+                warn!(
+                    "{}: Unpacked output of {:?} exceeds the budget for {:?} ({} bytes)",
+                    task_id, item_path, origin, total
+                );
+                let data = OutputData::BombDetected(total);
+                output_cb(Output::new(task_id, item_path, origin, "", &parent, data));
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn handle_data<I: Fn(Input), O: Fn(Output)>(
+        self,
+        factory: &InputFactory,
+        pre_processor: &PreProcessor,
+        input_cb: I,
+        output_cb: O,
+    ) -> io::Result<()> {
+        let parent = self
+            .parent_type
+            .as_ref()
+            .and_then(|x| pre_processor.plugins.get(x));
+        factory.stats.inputs.fetch_add(1, Ordering::Relaxed);
         match self.data {
             InputData::File(path, temp) => {
+                // Files extracted by a plugin only have the time they were written
+                let mtime = if temp {
+                    None
+                } else {
+                    fs::metadata(&path)?.modified().ok()
+                };
                 let file_buf = BufReader::with_capacity(BUFSIZE, File::open(&path)?);
-                if let Some(ppi) = pre_processor.pre_process(
-                    self.task_id,
-                    self.item_path,
-                    Some(&path),
-                    file_buf,
-                )? {
-                    run_task(input_cb, output_cb, factory, ppi)?;
+                if let Some(mut ppi) = factory.stats.count_type(
+                    pre_processor.pre_process(
+                        self.task_id,
+                        self.item_path,
+                        self.origin,
+                        Some(&path),
+                        None,
+                        file_buf,
+                    )?,
+                    &output_cb,
+                ) {
+                    ppi.mtime = mtime;
+                    check_produces(parent, &ppi, &output_cb);
+                    if skip_self_nested(self.parent_type.as_ref(), parent, &ppi, &output_cb)? {
+                        // Dropped as an unpack loop
+                    } else if let Some(digest) = factory.check_duplicate(&path)? {
+                        info!("{}: Skipping duplicate {:?}", ppi.task_id, ppi.item_path);
+                        ppi.plugin.remove_dirs()?;
+                        output_cb(ppi.output(OutputData::Duplicate(digest)));
+                    } else {
+                        run_task(input_cb, output_cb, factory, ppi)?;
+                    }
                 }
                 if temp {
-                    fs::remove_file(path)?;
+                    factory.remove_file(&path)?;
                 }
             }
             InputData::Stdin(stdin) => {
-                if let Some(ppi) =
-                    pre_processor.pre_process(self.task_id, self.item_path, None, stdin)?
-                {
-                    run_task(input_cb, output_cb, factory, ppi)?;
+                if let Some(ppi) = factory.stats.count_type(
+                    pre_processor.pre_process(
+                        self.task_id,
+                        self.item_path,
+                        self.origin,
+                        None,
+                        pre_processor.stdin_type.as_ref(),
+                        stdin,
+                    )?,
+                    &output_cb,
+                ) {
+                    check_produces(parent, &ppi, &output_cb);
+                    if !skip_self_nested(self.parent_type.as_ref(), parent, &ppi, &output_cb)? {
+                        run_task(input_cb, output_cb, factory, ppi)?;
+                    }
+                }
+            }
+            InputData::Pipe(path) => {
+                debug!("{}: Opening pipe {:?}", self.task_id, path);
+                let pipe = File::open(&path)?;
+                if let Some(ppi) = factory.stats.count_type(
+                    pre_processor.pre_process(
+                        self.task_id,
+                        self.item_path,
+                        self.origin,
+                        None,
+                        None,
+                        pipe,
+                    )?,
+                    &output_cb,
+                ) {
+                    check_produces(parent, &ppi, &output_cb);
+                    if !skip_self_nested(self.parent_type.as_ref(), parent, &ppi, &output_cb)? {
+                        run_task(input_cb, output_cb, factory, ppi)?;
+                    }
                 }
             }
             InputData::Stdout(stdout) => {
-                if let Some(ppi) =
-                    pre_processor.pre_process(self.task_id, self.item_path, None, stdout)?
-                {
-                    run_task(input_cb, output_cb, factory, ppi)?;
+                let writer = stdout.writer.clone();
+                let data = match pre_processor.sniff_timeout {
+                    Some(timeout) => match read_head(stdout, timeout)? {
+                        Some((head, stdout)) => Cursor::new(head).chain(stdout),
+                        None => {
+                            warn!(
+                                "{}: Timed out reading the start of {:?}, killing its unpacker",
+                                self.task_id, self.item_path
+                            );
+                            // Its task is waiting on it, and the pipes are closed with it
+                            writer.kill();
+                            output_cb(Output::new(
+                                self.task_id,
+                                self.item_path,
+                                self.origin,
+                                "",
+                                parent.map(|x| x.name.as_str()).unwrap_or(""),
+                                OutputData::Error(
+                                    "Timed out reading the start of the input".into(),
+                                ),
+                            ));
+                            return Ok(());
+                        }
+                    },
+                    None => Cursor::new(Vec::new()).chain(stdout),
+                };
+                if let Some(ppi) = factory.stats.count_type(
+                    pre_processor.pre_process(
+                        self.task_id,
+                        self.item_path,
+                        self.origin,
+                        None,
+                        None,
+                        data,
+                    )?,
+                    &output_cb,
+                ) {
+                    check_produces(parent, &ppi, &output_cb);
+                    if !skip_self_nested(self.parent_type.as_ref(), parent, &ppi, &output_cb)? {
+                        run_task(input_cb, output_cb, factory, ppi)?;
+                    }
                 }
             }
         }
@@ -84,18 +659,61 @@ impl Input {
 pub enum InputData {
     File(PathBuf, bool),
     Stdin(Stdin),
-    Stdout(ChildStdout),
+    Stdout(BudgetReader),
+    // A FIFO or device, opened and read as a stream by the input thread
+    Pipe(PathBuf),
 }
 
 impl InputData {
     pub fn is_stdout(&self) -> bool {
-        match self {
-            &InputData::Stdout(_) => true,
-            _ => false,
+        matches!(self, InputData::Stdout(_))
+    }
+}
+
+// Flags inputs unpacked by a plugin that aren't of a type it declared to produce
+fn check_produces<O: Fn(Output), R>(
+    parent: Option<&Plugin>,
+    ppi: &PreProcessedInput<R>,
+    output_cb: O,
+) {
+    let parent = match parent {
+        Some(x) => x,
+        None => return,
+    };
+    if let Some(produces) = &parent.produces {
+        if !produces.contains(&ppi.item_type) {
+            warn!(
+                "{}: Plugin {} produced {:?} of unexpected type: {}",
+                ppi.task_id, parent.name, ppi.item_path, ppi.item_type
+            );
+            let mut output = ppi.output(OutputData::UnexpectedType);
+            output.plugin_name = parent.name.clone();
+            output_cb(output);
         }
     }
 }
 
+// Drops an input of the same type as the unpacker it came from, unless the plugin allows it
+fn skip_self_nested<O: Fn(Output), R>(
+    parent_type: Option<&FileType>,
+    parent: Option<&Plugin>,
+    ppi: &PreProcessedInput<R>,
+    output_cb: O,
+) -> io::Result<bool> {
+    match parent {
+        Some(parent) if !parent.allows_self_nesting() && parent_type == Some(&ppi.item_type) => {
+            warn!(
+                "{}: Dropping {:?}, plugin {} produced its own type: {}",
+                ppi.task_id, ppi.item_path, parent.name, ppi.item_type
+            );
+            ppi.plugin.remove_dirs()?;
+            output_cb(ppi.output(OutputData::Skipped(SkipReason::SelfNested)));
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
 fn run_task<I, O, R>(
     input_cb: I,
     output_cb: O,
@@ -105,7 +723,7 @@ fn run_task<I, O, R>(
 where
     I: Fn(Input),
     O: Fn(Output),
-    R: Read,
+    R: Read + Send,
 {
     let input_exists = ppi
         .plugin
@@ -113,102 +731,539 @@ where
         .file()
         .map(|x| x.exists())
         .unwrap_or(true);
+    let plugin_stats = factory.stats.plugin(&ppi.plugin.plugin_name);
+    plugin_stats.tasks.fetch_add(1, Ordering::Relaxed);
+    // Size, sha256 and bytes hashed of the task data, for the container record of an unpacker
+    let mut container = None;
     if !input_exists {
         let path = ppi.plugin.input_path.file().unwrap();
         debug!("{}: Creating input file {:?}", ppi.task_id, path);
         let mut file = File::create(path)?;
-        io::copy(&mut ppi.data, &mut file)?;
-    }
-    if let Some(path) = ppi.plugin.output_path.dir() {
-        debug!("{}: Creating dir {:?}", ppi.task_id, path);
-        fs::create_dir(path)?;
-    }
-
-    let mut child = ppi.plugin.command.spawn()?;
-    output_cb(Output::new(
-        ppi.task_id,
-        ppi.item_path.clone(),
-        ppi.item_type.clone(),
-        ppi.plugin.plugin_name.clone(),
-        OutputData::LogStderr(child.stderr.take().unwrap()),
-    ));
-    let stdout = child.stdout.take().unwrap();
-    if ppi.plugin.output_path.stdout() {
-        if ppi.plugin.unpacker {
-            input_cb(factory.new_input(ppi.item_path.clone(), InputData::Stdout(stdout)));
-        } else {
-            output_cb(Output::new(
+        let mut data = HashingReader::with_limit(&mut ppi.data, factory.hash_bytes);
+        io::copy(&mut data, &mut file)?;
+        let (size, hashed) = (data.bytes_read(), data.hashed_bytes());
+        let digest = data.digest();
+        debug!("{}: Task data sha256: {}", ppi.task_id, digest);
+        container = Some((size, digest, hashed));
+    }
+
+    if let Some(filter) = &mut ppi.plugin.filter {
+        debug!("{}: Running filter {:?}", ppi.task_id, filter);
+        let dropped = match filter.output() {
+            Ok(out) => {
+                for line in String::from_utf8_lossy(&out.stderr).lines() {
+                    info!("FILTER {}: {}", ppi.plugin.plugin_name, line);
+                }
+                (!out.status.success()).then(|| OutputData::Skipped(SkipReason::Filtered))
+            }
+            Err(err) => {
+                error!("{}: Failed to run filter: {}", ppi.task_id, err);
+                factory.stats.plugin_errors.fetch_add(1, Ordering::Relaxed);
+                plugin_stats.errors.fetch_add(1, Ordering::Relaxed);
+                Some(OutputData::Error(format!("Failed to run filter: {}", err)))
+            }
+        };
+        if let Some(data) = dropped {
+            debug!("{}: Dropped {:?} by filter", ppi.task_id, ppi.item_path);
+            if !input_exists {
+                factory.remove_file(ppi.plugin.input_path.file().unwrap())?;
+            }
+            ppi.plugin.remove_dirs()?;
+            factory.count_progress();
+            output_cb(ppi.output(data));
+            return Ok(());
+        }
+    }
+
+    let (data, mut ppi) = ppi.take_data();
+    debug!(
+        "{}: Input path {:?}, output path {:?}",
+        ppi.task_id, ppi.plugin.input_path, ppi.plugin.output_path
+    );
+    // Anything it unpacks would be over budget, so it isn't spawned
+    let over = ppi
+        .plugin
+        .unpacker
+        .then(|| factory.budget.charge(&ppi.origin, 0));
+    if let Some(total) = over.flatten() {
+        warn!(
+            "{}: Not unpacking {:?}, the budget for {:?} is exceeded",
+            ppi.task_id, ppi.item_path, ppi.origin
+        );
+        if !input_exists {
+            factory.remove_file(ppi.plugin.input_path.file().unwrap())?;
+        }
+        ppi.plugin.remove_dirs()?;
+        factory.count_progress();
+        output_cb(ppi.output(OutputData::BombDetected(total)));
+        return Ok(());
+    }
+    if let Some(limiter) = &factory.spawn_limiter {
+        let start = Instant::now();
+        if !limiter.acquire(factory.throttle_wait) {
+            let dropped = factory.throttle_action == ThrottleAction::Drop;
+            let waited = start.elapsed();
+            warn!(
+                "{}: Throttled after waiting {:?} to spawn {}{}",
                 ppi.task_id,
-                ppi.item_path.clone(),
-                ppi.item_type.clone(),
-                ppi.plugin.plugin_name.clone(),
-                OutputData::Stdout(stdout),
-            ));
-        }
-    } else {
-        output_cb(Output::new(
-            ppi.task_id,
-            ppi.item_path.clone(),
-            ppi.item_type.clone(),
-            ppi.plugin.plugin_name.clone(),
-            OutputData::LogStdout(stdout),
-        ));
-    }
-    if ppi.plugin.input_path.stdin() {
-        debug!("{}: Copy task data to child stdin", ppi.task_id);
-        io::copy(&mut ppi.data, child.stdin.as_mut().unwrap())?;
-    }
-    child.wait()?;
+                waited,
+                ppi.plugin.plugin_name,
+                if dropped { ", dropping it" } else { "" }
+            );
+            factory.stats.throttled.fetch_add(1, Ordering::Relaxed);
+            output_cb(ppi.output(OutputData::Throttled(waited.as_millis() as u64, dropped)));
+            if dropped {
+                if !input_exists {
+                    factory.remove_file(ppi.plugin.input_path.file().unwrap())?;
+                }
+                ppi.plugin.remove_dirs()?;
+                factory.count_progress();
+                return Ok(());
+            }
+            limiter.acquire(None);
+        }
+    }
+    let mut child = match ppi.plugin.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            factory.stats.plugin_errors.fetch_add(1, Ordering::Relaxed);
+            plugin_stats.errors.fetch_add(1, Ordering::Relaxed);
+            // Logged, so they don't take the place of the spawn error
+            if !input_exists {
+                let path = ppi.plugin.input_path.file().unwrap();
+                if let Err(err) = factory.remove_file(path) {
+                    warn!("{}: Failed to remove {:?}: {}", ppi.task_id, path, err);
+                }
+            }
+            if let Err(err) = ppi.plugin.remove_dirs() {
+                warn!("{}: Failed to remove the plugin dirs: {}", ppi.task_id, err);
+            }
+            factory.count_progress();
+            // Reported here with the plugin, so it isn't returned as an error of the input
+            error!("{}: Failed to spawn plugin: {}", ppi.task_id, err);
+            output_cb(ppi.output(OutputData::Error(format!(
+                "Failed to spawn plugin: {}",
+                err
+            ))));
+            return Ok(());
+        }
+    };
+    let mut stdout = child.stdout.take().unwrap();
+    let mut post = None;
+    if let Some(cmd) = &mut ppi.plugin.post {
+        debug!("{}: Spawning post command {:?}", ppi.task_id, cmd);
+        match cmd.stdin(stdout).spawn() {
+            Ok(mut post_child) => {
+                stdout = post_child.stdout.take().unwrap();
+                post = Some(post_child);
+            }
+            Err(err) => {
+                child.kill()?;
+                child.wait()?;
+                factory.stats.plugin_errors.fetch_add(1, Ordering::Relaxed);
+                plugin_stats.errors.fetch_add(1, Ordering::Relaxed);
+                if !input_exists {
+                    factory.remove_file(ppi.plugin.input_path.file().unwrap())?;
+                }
+                // The plugin may have written to its dirs before it was killed
+                let dirs = ppi.plugin.output_paths().filter_map(OutputPath::dir);
+                for path in dirs.chain(ppi.plugin.scratch_dir.as_ref()) {
+                    factory.remove_dir_all(path)?;
+                }
+                factory.count_progress();
+                error!("{}: Failed to spawn post command: {}", ppi.task_id, err);
+                output_cb(ppi.output(OutputData::Error(format!(
+                    "Failed to spawn post command: {}",
+                    err
+                ))));
+                return Ok(());
+            }
+        }
+    }
+    let lines = LineCount::default();
+    // Inputs scheduled from the output of an unpacker
+    let mut child_count = 0;
+    // Set when the stdout of the unpacker goes over budget
+    let mut exceeded = None;
+    let input_file = ppi
+        .plugin
+        .input_path
+        .file()
+        .filter(|_| !input_exists)
+        .cloned();
+    let stdin = child.stdin.take().filter(|_| ppi.plugin.input_path.stdin());
+    let task_id = ppi.task_id;
+    let chunk_size = ppi.plugin.stdin_chunk_size;
+    let emit_container = ppi.plugin.emit_container;
+    let hash_bytes = factory.hash_bytes;
+    // Of the process writing the stdout that is read
+    let stdout_writer = KillHandle::new(post.as_ref().unwrap_or(&child));
+    let written = thread::scope(|scope| {
+        // Written on its own thread, started before the output is read, so a plugin that
+        // writes output before reading all of its input can't block on the copy
+        let writer = stdin.map(|stdin| {
+            scope.spawn(move || {
+                let input_file = input_file.as_deref();
+                write_stdin(
+                    task_id,
+                    stdin,
+                    data,
+                    input_file,
+                    chunk_size,
+                    emit_container,
+                    hash_bytes,
+                )
+            })
+        });
+        output_cb(ppi.output(OutputData::LogStderr(
+            child.stderr.take().unwrap(),
+            ppi.plugin.emit_logs,
+        )));
+        if let Some(post_child) = &mut post {
+            output_cb(ppi.output(OutputData::LogStderr(
+                post_child.stderr.take().unwrap(),
+                ppi.plugin.emit_logs,
+            )));
+        }
+        if ppi.plugin.output_path.stdout() {
+            if ppi.plugin.unpacker {
+                output_cb(ppi.output(OutputData::Stage));
+                let stdout = BudgetReader::new(
+                    stdout,
+                    factory.budget.clone(),
+                    ppi.origin.clone(),
+                    stdout_writer.clone(),
+                );
+                exceeded = Some(stdout.exceeded.clone());
+                input_cb(factory.new_child_input(
+                    ppi.item_path.clone(),
+                    &ppi,
+                    InputData::Stdout(stdout),
+                ));
+                child_count += 1;
+            } else {
+                let mut stdout_output = ppi.output(OutputData::Stdout(stdout, lines.clone()));
+                stdout_output.plugin_stats = Some(plugin_stats.clone());
+                output_cb(stdout_output);
+            }
+        } else {
+            output_cb(ppi.output(OutputData::LogStdout(stdout, ppi.plugin.emit_logs)));
+        }
+        writer.map(|x| x.join().unwrap()).transpose()
+    })?;
+    if let Some(written) = written.flatten() {
+        container = Some(written);
+    }
+    let (status, cpu_time) = match post {
+        Some(_) => plugin::wait_child(&mut child)?,
+        None => stdout_writer.wait(&mut child)?,
+    };
+    // Stopped by closing its stdout, the bomb is reported by the input reading it
+    let stopped = exceeded.is_some_and(|x| x.load(Ordering::Relaxed) > 0);
+    if !status.success() && !stopped {
+        warn!("{}: Plugin exited with {}", ppi.task_id, status);
+        factory.stats.plugin_errors.fetch_add(1, Ordering::Relaxed);
+        plugin_stats.errors.fetch_add(1, Ordering::Relaxed);
+        if let Some(msg) = ppi.plugin.check_limits(&status, cpu_time) {
+            error!("{}: {}", ppi.task_id, msg);
+            output_cb(ppi.output(OutputData::Error(msg)));
+        }
+    }
+    if let Some(mut post) = post {
+        let (status, _) = stdout_writer.wait(&mut post)?;
+        if !status.success() && !stopped {
+            warn!("{}: Post command exited with {}", ppi.task_id, status);
+            factory.stats.plugin_errors.fetch_add(1, Ordering::Relaxed);
+            plugin_stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
     debug!("{}: FINISH CHILD PROCESS", ppi.task_id);
+    factory.count_progress();
+    if let Some(path) = &ppi.plugin.scratch_dir {
+        factory.remove_dir_all(path)?;
+    }
 
     if !input_exists {
-        fs::remove_file(ppi.plugin.input_path.file().unwrap())?;
+        factory.remove_file(ppi.plugin.input_path.file().unwrap())?;
     }
-    match ppi.plugin.output_path {
-        OutputPath::Dir(path) => {
-            if ppi.plugin.unpacker {
-                walk::walk_dir(path, ppi.item_path, |p, ip| {
-                    input_cb(factory.new_input(ip, InputData::File(p, true)));
-                })?
-            } else {
-                let task_id = ppi.task_id;
-                let plugin_name = ppi.plugin.plugin_name;
-                let item_type = ppi.item_type;
-                let item_path = ppi.item_path;
-                walk::walk_dir(path, item_path.clone(), |p, _| {
-                    output_cb(Output::new(
-                        task_id,
-                        item_path.clone(),
-                        item_type.clone(),
-                        plugin_name.clone(),
-                        OutputData::File(p),
-                    ));
-                })?
+    let mismatches = ppi
+        .plugin
+        .output_paths()
+        .filter_map(OutputPath::check_kind)
+        .collect::<Vec<_>>();
+    if !mismatches.is_empty() {
+        let msg = mismatches.join("; ");
+        error!("{}: {} (check output type in config)", ppi.task_id, msg);
+        factory.stats.plugin_errors.fetch_add(1, Ordering::Relaxed);
+        plugin_stats.errors.fetch_add(1, Ordering::Relaxed);
+        // None of the outputs are used, whatever kind they are
+        for path in ppi.plugin.output_paths().filter_map(OutputPath::path) {
+            if path.is_dir() {
+                factory.remove_dir_all(path)?;
+            } else if path.exists() {
+                factory.remove_file(path)?;
             }
         }
-        OutputPath::File(path) => {
-            if ppi.plugin.unpacker {
-                input_cb(factory.new_input(ppi.item_path, InputData::File(path, true)));
-            } else {
-                let output = Output::new(
-                    ppi.task_id,
-                    ppi.item_path,
-                    ppi.item_type,
-                    ppi.plugin.plugin_name,
-                    OutputData::File(path),
-                );
-                output_cb(output);
+        output_cb(ppi.output(OutputData::Error(msg)));
+        return Ok(());
+    }
+    if ppi.plugin.unpacker {
+        let mut children = Vec::new();
+        for output_path in ppi.plugin.output_paths() {
+            match output_path {
+                OutputPath::Dir(path) | OutputPath::Both(path) => {
+                    walk::walk_dir(path.clone(), "".into(), false, true, |p, ip| {
+                        children.push((p, factory.nested_path(&ppi.item_path, ip)))
+                    })?;
+                }
+                OutputPath::File(path) if ppi.plugin.output_optional && !path.exists() => {}
+                OutputPath::File(path) => children.push((path.clone(), ppi.item_path.clone())),
+                OutputPath::Stdout => {}
+            }
+        }
+        let mut bytes = 0;
+        for (path, _) in children.iter() {
+            bytes += fs::metadata(path)?.len();
+        }
+        // A stdout over budget is reported by the input reading it
+        let over = if children.is_empty() {
+            None
+        } else {
+            factory.budget.charge(&ppi.origin, bytes)
+        };
+        if let Some(total) = over {
+            warn!(
+                "{}: Unpacked output of {:?} exceeds the budget for {:?} ({} bytes)",
+                ppi.task_id, ppi.item_path, ppi.origin, total
+            );
+            for output_path in ppi.plugin.output_paths() {
+                match output_path {
+                    OutputPath::Dir(path) | OutputPath::Both(path) => {
+                        factory.remove_dir_all(path)?
+                    }
+                    OutputPath::File(path) => factory.remove_file(path)?,
+                    OutputPath::Stdout => {}
+                }
+            }
+            output_cb(ppi.output(OutputData::BombDetected(total)));
+        } else {
+            // Reported above when the stdout of the unpacker was scheduled
+            if !ppi.plugin.output_path.stdout() {
+                output_cb(ppi.output(OutputData::Stage));
+            }
+            child_count += children.len() as u64;
+            for (path, item_path) in children {
+                input_cb(factory.new_child_input(item_path, &ppi, InputData::File(path, true)));
+            }
+            output_cb(ppi.output(OutputData::Children(child_count)));
+            if ppi.plugin.emit_container {
+                let (size, digest, hashed) = match container {
+                    Some(x) => x,
+                    // The plugin read the input file itself
+                    None => {
+                        let path = ppi.plugin.input_path.file().unwrap();
+                        let file = File::open(path)?;
+                        let size = file.metadata()?.len();
+                        // Past the hashed part only the size is needed
+                        let limit = factory.hash_bytes.unwrap_or(u64::MAX);
+                        let mut file = HashingReader::new(file.take(limit));
+                        io::copy(&mut file, &mut io::sink())?;
+                        let hashed = file.bytes_read();
+                        (size, file.digest(), hashed)
+                    }
+                };
+                output_cb(ppi.output(OutputData::Container(size, digest, hashed)));
+            }
+        }
+        return Ok(());
+    }
+    let output = |data| {
+        let mut output = ppi.output(data);
+        output.plugin_stats = Some(plugin_stats.clone());
+        output
+    };
+    for output_path in ppi.plugin.output_paths() {
+        match output_path {
+            OutputPath::Dir(path) | OutputPath::Both(path) => {
+                // Records keep the path of the file within the output dir
+                walk::walk_dir(path.clone(), ppi.item_path.clone(), false, true, |p, ip| {
+                    let mut file_output = output(OutputData::File(p, lines.clone()));
+                    file_output.item_path = ip;
+                    output_cb(file_output);
+                })?
             }
+            OutputPath::File(path) => {
+                output_cb(output(OutputData::File(path.clone(), lines.clone())))
+            }
+            OutputPath::Stdout => {}
         }
-        _ => {}
+    }
+    // Otherwise the last data output to finish reports it
+    if Arc::into_inner(lines).map(AtomicU64::into_inner) == Some(0) {
+        output_cb(output(OutputData::Processed));
     }
     Ok(())
 }
 
+// Returns the size, sha256 and bytes hashed of the task data when it's read from the input,
+// not from the input file it was already written to
+fn write_stdin<R: Read>(
+    task_id: TaskId,
+    mut stdin: ChildStdin,
+    mut data: R,
+    input_file: Option<&Path>,
+    chunk_size: Option<usize>,
+    emit_container: bool,
+    hash_bytes: Option<u64>,
+) -> io::Result<Option<(u64, String, u64)>> {
+    debug!("{}: Copy task data to child stdin", task_id);
+    let mut container = None;
+    let result = match input_file {
+        None => {
+            let mut data = HashingReader::with_limit(&mut data, hash_bytes);
+            let result = copy_chunked(&mut data, &mut stdin, chunk_size);
+            let broken_pipe = matches!(&result, Err(err) if err.kind() == ErrorKind::BrokenPipe);
+            if broken_pipe && emit_container {
+                // The rest is still read for the container record
+                io::copy(&mut data, &mut io::sink())?;
+            }
+            if result.is_ok() || broken_pipe && emit_container {
+                let (size, hashed) = (data.bytes_read(), data.hashed_bytes());
+                let digest = data.digest();
+                debug!("{}: Task data sha256: {}", task_id, digest);
+                container = Some((size, digest, hashed));
+            }
+            result
+        }
+        // The task data was already consumed into the input file
+        Some(path) => copy_chunked(&mut File::open(path)?, &mut stdin, chunk_size),
+    };
+    match result {
+        // The plugin only needed part of its input
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+            debug!("{}: Child closed stdin early", task_id)
+        }
+        result => {
+            result?;
+        }
+    }
+    Ok(container)
+}
+
+// Writes in chunks of the given size, or as io::copy does without one
+fn copy_chunked<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    chunk_size: Option<usize>,
+) -> io::Result<u64> {
+    let chunk_size = match chunk_size {
+        Some(x) => x,
+        None => return io::copy(reader, writer),
+    };
+    let mut buf = vec![0; chunk_size];
+    let mut total = 0;
+    loop {
+        // Filled up before writing, so every write but the last is a whole chunk
+        let mut len = 0;
+        while len < chunk_size {
+            match reader.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if len == 0 {
+            return Ok(total);
+        }
+        writer.write_all(&buf[..len])?;
+        total += len as u64;
+    }
+}
+
+// Runs a plugin once on its test input for --selftest, returns why it failed if it did
+pub fn self_test(file_type: &str, plugin: &Plugin, working_dir: &Path) -> Result<(), String> {
+    let data = match &plugin.test_input {
+        Some(path) => {
+            fs::read(path).map_err(|err| format!("Failed to read {:?}: {}", path, err))?
+        }
+        None => SELFTEST_INPUT.to_vec(),
+    };
+    let factory = InputFactory::new(false);
+    let ppi = PreProcessedInput {
+        task_id: factory.new_task_id(),
+        item_path: "".into(),
+        origin: "".into(),
+        item_type: file_type.into(),
+        detector_rule: None,
+        binary: false,
+        mtime: None,
+        tags: Vec::new(),
+        plugin: plugin
+            .prep(None, &data, working_dir)
+            .map_err(|err| err.to_string())?,
+        data: Cursor::new(data),
+    };
+    let context = OutputContext::default();
+    let mut errors = Vec::new();
+    thread::scope(|scope| {
+        // Outputs are read while the plugin runs, as it may block on writing them
+        let readers = Mutex::new(Vec::new());
+        let result =
+            run_task(
+                |input| {
+                    if let InputData::Stdout(mut stdout) = input.data {
+                        readers.lock().unwrap().push(scope.spawn(move || {
+                            io::copy(&mut stdout, &mut io::sink()).map(|_| Vec::new())
+                        }));
+                    }
+                },
+                |output| {
+                    let context = &context;
+                    readers.lock().unwrap().push(scope.spawn(move || {
+                        let mut records = Vec::new();
+                        output.handle(context, &mut records).map(|_| records)
+                    }));
+                },
+                &factory,
+                ppi,
+            );
+        if let Err(err) = result {
+            errors.push(err.to_string());
+        }
+        for reader in readers.into_inner().unwrap() {
+            match reader.join().unwrap() {
+                Ok(records) => {
+                    for record in
+                        serde_json::Deserializer::from_slice(&records).into_iter::<Value>()
+                    {
+                        if let Some(msg) = record
+                            .ok()
+                            .and_then(|x| x["error"].as_str().map(String::from))
+                        {
+                            errors.push(msg);
+                        }
+                    }
+                }
+                Err(err) => errors.push(err.to_string()),
+            }
+        }
+    });
+    match errors.into_iter().next() {
+        Some(msg) => Err(msg),
+        None if factory.stats.plugin_errors.load(Ordering::Relaxed) > 0 => {
+            Err("Plugin exited with an error".into())
+        }
+        None => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::cell::RefCell;
+    use std::env;
     use std::io::{Cursor, Write};
     use std::sync::Arc;
     use std::sync::Mutex;
@@ -217,40 +1272,1051 @@ mod tests {
 
     use serde_json::Value;
 
-    use crate::plugin::{OutputType, Plugin};
+    use crate::output::OutputContext;
+    use crate::plugin::{
+        gen_path, Config, DetectionOrder, ExtraOutput, Filter, InputType, OutputType, Plugin,
+        PostCommand,
+    };
+    use crate::pre_process::SNIFF_SIZE;
 
     #[test]
+    #[cfg(unix)]
     fn test_run_task() {
-        let factory = InputFactory::new();
+        let factory = InputFactory::new(false);
         let plugin = Plugin {
             name: "foo".into(),
             path: "/bin/sh".into(),
             args: Some(vec!["$INPUT".into()]),
-            input: None,
             output: Some(OutputType::stdout),
-            unpacker: None,
+            ..Default::default()
         };
         let task = PreProcessedInput {
-            task_id: TaskId::new(0),
-            item_path: "".into(),
-            item_type: "".into(),
-            plugin: plugin.prep(None).unwrap(),
-            data: Cursor::new(Vec::from(*b"#!/bin/sh\necho foobar")),
+            origin: "/foo/bar".into(),
+            detector_rule: Some("^#!".into()),
+            ..task(&plugin, b"#!/bin/sh\necho foobar")
         };
-        let cur = SharedCursor::new();
-        let cur_clone = cur.clone();
-        run_task(
-            &drop,
-            move |x| x.handle(&mut cur_clone.clone()).unwrap(),
-            &factory,
-            task,
-        )
-        .unwrap();
-        let result: Value = serde_json::from_slice(&cur.into_inner()).unwrap();
-        assert_eq!(
-            result.as_object().unwrap().get("data").unwrap(),
-            &Value::String("foobar".into())
-        );
+        let records = run(&factory, task);
+        assert_eq!(1, records.len());
+        assert_eq!(Some(&Value::from("foobar")), records[0].get("data"));
+        assert_eq!(Some(&Value::from("job")), records[0].get("job_id"));
+        assert_eq!(Some(&Value::from("/foo/bar")), records[0].get("origin"));
+        assert_eq!(Some(&Value::from("^#!")), records[0].get("detector_rule"));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_run_task_windows() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "cmd".into(),
+            args: Some(vec!["/C".into(), "echo foobar".into()]),
+            output: Some(OutputType::stdout),
+            ..Default::default()
+        };
+        let records = run(&factory, task(&plugin, b""));
+        assert_eq!(Some(&Value::from("foobar")), records[0].get("data"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_both() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "cat $INPUT -".into()]),
+            input: Some(InputType::both),
+            output: Some(OutputType::stdout),
+            ..Default::default()
+        };
+        let records = run(&factory, task(&plugin, b"foobar"));
+        assert_eq!(Some(&Value::from("foobarfoobar")), records[0].get("data"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_stdin_closed_early() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "head -c 3".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::stdout),
+            ..Default::default()
+        };
+        let records = run(&factory, task(&plugin, &vec![b'x'; 1024 * 1024]));
+        assert_eq!(Some(&Value::from("xxx")), records[0].get("data"));
+    }
+
+    #[test]
+    fn test_run_task_throttled() {
+        let mut factory = InputFactory::new(false);
+        let limiter = RateLimiter::new(1);
+        assert!(limiter.acquire(None));
+        factory.spawn_limiter = Some(limiter);
+        factory.throttle_wait = Some(Duration::from_millis(10));
+        factory.throttle_action = ThrottleAction::Drop;
+        let records = run(&factory, task(&cat(), b"foo"));
+        assert_eq!(1, records.len());
+        assert_eq!(Some(&Value::from(true)), records[0].get("throttled"));
+        assert_eq!(Some(&Value::from(true)), records[0].get("dropped"));
+        assert!(records[0]["waited_ms"].as_u64().unwrap() >= 10);
+        assert_eq!(1, factory.stats.throttled.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_throttled_wait() {
+        let mut factory = InputFactory::new(false);
+        let limiter = RateLimiter::new(10);
+        assert!(limiter.acquire(None));
+        factory.spawn_limiter = Some(limiter);
+        factory.throttle_wait = Some(Duration::from_millis(30));
+        let records = run(&factory, task(&cat(), b"foo"));
+        let throttled = records.iter().filter(|x| x.get("throttled").is_some());
+        assert_eq!(
+            vec![&Value::from(false)],
+            throttled.map(|x| &x["dropped"]).collect::<Vec<_>>()
+        );
+        assert_eq!(1, factory.stats.throttled.load(Ordering::Relaxed));
+        assert!(records.iter().any(|x| x["data"] == "foo"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_stdin_large() {
+        let factory = InputFactory::new(false);
+        let records = run(&factory, task(&cat(), &b"x\n".repeat(256 * 1024)));
+        let lines = records.iter().filter(|x| x["data"] == "x").count();
+        assert_eq!(256 * 1024, lines);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_output_both() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec![
+                "-c".into(),
+                "echo report; mkdir $OUTPUT/b; echo artifact > $OUTPUT/b/a".into(),
+            ]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::both),
+            ..Default::default()
+        };
+        let task = PreProcessedInput {
+            item_path: "x".into(),
+            ..task(&plugin, b"")
+        };
+        let output_dir = task.plugin.output_path.dir().unwrap().clone();
+        let records = run(&factory, task);
+        fs::remove_dir_all(output_dir).unwrap();
+        // Leaves out the digest record of the output file
+        let mut data = records
+            .into_iter()
+            .filter(|x| x.get("data").is_some())
+            .map(|x| (x["data"].clone(), x["path"].clone()))
+            .collect::<Vec<(Value, Value)>>();
+        data.sort_by_key(|x| x.0.to_string());
+        assert_eq!(
+            data,
+            vec![
+                (Value::from("artifact"), Value::from("x/b/a")),
+                (Value::from("report"), Value::from("x"))
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_emit_logs() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "echo info; echo oops >&2".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::dir),
+            emit_logs: Some(true),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"");
+        let output_dir = task.plugin.output_path.dir().unwrap().clone();
+        let records = run(&factory, task);
+        fs::remove_dir_all(output_dir).unwrap();
+        let mut records = records
+            .into_iter()
+            .map(|x| (x["level"].clone(), x["data"].clone()))
+            .collect::<Vec<(Value, Value)>>();
+        records.sort_by_key(|x| x.0.to_string());
+        assert_eq!(
+            records,
+            vec![
+                (Value::from("stderr"), Value::from("oops")),
+                (Value::from("stdout"), Value::from("info")),
+                // The output dir is empty
+                (Value::Null, Value::Null)
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_processed() {
+        let mut factory = InputFactory::new(false);
+        let progress = Arc::new(AtomicUsize::new(0));
+        factory.progress = Some(progress.clone());
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/true".into(),
+            output: Some(OutputType::stdout),
+            ..Default::default()
+        };
+        let records = run(&factory, task(&plugin, b""));
+        assert_eq!(Some(&Value::from(true)), records[0].get("processed"));
+        assert_eq!(Some(&Value::from(0)), records[0].get("lines"));
+        assert_eq!(1, progress.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_scratch_dir() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "touch $OUTPUT/bar && ls $OUTPUT".into()]),
+            output: Some(OutputType::stdout),
+            scratch_dir: Some(true),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"");
+        let scratch_dir = task.plugin.scratch_dir.clone().unwrap();
+        let records = run(&factory, task);
+        assert_eq!(Some(&Value::from("bar")), records[0].get("data"));
+        assert!(!scratch_dir.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_handle_pipe() {
+        let working_dir = gen_path(&env::temp_dir());
+        fs::create_dir(&working_dir).unwrap();
+        let fifo = working_dir.join("fifo");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let config: Config = serde_yaml::from_str(
+            "text: {header: {regex: ^foo}, plugin: {name: cat, path: /bin/cat, args: [$INPUT], \
+             output: stdout}}",
+        )
+        .unwrap();
+        let pre_processor = PreProcessor::new(&config, DetectionOrder::best, working_dir.clone());
+        let factory = InputFactory::new(false);
+        let writer_path = fifo.clone();
+        let writer = thread::spawn(move || fs::write(writer_path, b"foobar\n").unwrap());
+        let input = factory.new_input("", fifo.clone(), InputData::Pipe(fifo));
+        let cur = SharedCursor::new();
+        let cur_clone = cur.clone();
+        input
+            .handle(&factory, &pre_processor, drop, move |x| {
+                x.handle(&OutputContext::default(), &mut cur_clone.clone())
+                    .unwrap()
+            })
+            .unwrap();
+        writer.join().unwrap();
+        fs::remove_dir_all(working_dir).unwrap();
+        let result: Value = serde_json::from_slice(&cur.into_inner()).unwrap();
+        assert_eq!(Some(&Value::from("foobar")), result.get("data"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_post() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/echo".into(),
+            args: Some(vec!["foobar".into()]),
+            output: Some(OutputType::stdout),
+            post: Some(PostCommand {
+                path: "tr".into(),
+                args: Some(vec!["a-z".into(), "A-Z".into()]),
+            }),
+            ..Default::default()
+        };
+        let records = run(&factory, task(&plugin, b""));
+        assert_eq!(Some(&Value::from("FOOBAR")), records[0].get("data"));
+        assert_eq!(0, factory.stats.plugin_errors.load(Ordering::Relaxed));
+        let plugin_stats = factory.stats.plugin("foo");
+        assert_eq!(1, plugin_stats.tasks.load(Ordering::Relaxed));
+        assert_eq!(0, plugin_stats.errors.load(Ordering::Relaxed));
+        assert_eq!(7, plugin_stats.bytes.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_post_spawn_error() {
+        let mut factory = InputFactory::new(false);
+        let progress = Arc::new(AtomicUsize::new(0));
+        factory.progress = Some(progress.clone());
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/cat".into(),
+            input: Some(InputType::file),
+            output: Some(OutputType::stdout),
+            scratch_dir: Some(true),
+            post: Some(PostCommand {
+                path: "/nonexistent".into(),
+                args: None,
+            }),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"foobar");
+        let input_path = task.plugin.input_path.file().unwrap().clone();
+        let scratch_dir = task.plugin.scratch_dir.clone().unwrap();
+        let records = run(&factory, task);
+        assert!(!input_path.exists());
+        assert!(!scratch_dir.exists());
+        assert_eq!(1, records.len());
+        assert!(records[0].get("error").is_some());
+        assert_eq!(Some(&Value::from("foo")), records[0].get("plugin"));
+        assert_eq!(1, factory.stats.plugin_errors.load(Ordering::Relaxed));
+        assert_eq!(
+            1,
+            factory.stats.plugin("foo").errors.load(Ordering::Relaxed)
+        );
+        assert_eq!(1, progress.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_output_optional() {
+        let run = |output_optional| {
+            let factory = InputFactory::new(false);
+            let plugin = Plugin {
+                name: "foo".into(),
+                path: "/bin/true".into(),
+                output_optional: Some(output_optional),
+                ..Default::default()
+            };
+            let errors = Mutex::new(0);
+            let output_cb = |x: Output| {
+                if x.handle(&OutputContext::default(), &mut io::sink())
+                    .is_err()
+                {
+                    *errors.lock().unwrap() += 1;
+                }
+            };
+            run_task(drop, output_cb, &factory, task(&plugin, b"")).unwrap();
+            errors.into_inner().unwrap()
+        };
+        assert_eq!(1, run(false));
+        assert_eq!(0, run(true));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_stdin_chunk_size() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "wc".into(),
+            args: Some(vec!["-c".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::stdout),
+            stdin_chunk_size: Some(4099),
+            ..Default::default()
+        };
+        let len = 1024 * 1024 + 3;
+        let records = run(&factory, task(&plugin, &vec![b'a'; len]));
+        assert_eq!(Some(&Value::from(len)), records[0].get("data"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_emit_container() {
+        let factory = InputFactory::new(false);
+        // Only reads part of its input
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "head -c 2 > a".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::dir),
+            unpacker: Some(true),
+            emit_container: Some(true),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"foobar");
+        let output_dir = task.plugin.output_path.dir().unwrap().clone();
+        let (records, children) = run_inputs(&factory, task);
+        fs::remove_dir_all(output_dir).unwrap();
+        assert_eq!(1, children.len());
+        let container = records.iter().find(|x| x.get("container").is_some());
+        let container = container.unwrap();
+        assert_eq!(Some(&Value::from(6)), container.get("size"));
+        assert_eq!(
+            Some(&Value::from(
+                "c3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2"
+            )),
+            container.get("sha256")
+        );
+        assert_eq!(Some(&Value::from(false)), container.get("sha256_partial"));
+        assert_eq!(Some(&Value::from(6)), container.get("sha256_bytes"));
+    }
+
+    #[test]
+    fn test_copy_chunked() {
+        let data = (0..10_000).map(|x| x as u8).collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        let total = copy_chunked(&mut &data[..], &mut buf, Some(7)).unwrap();
+        assert_eq!(data.len() as u64, total);
+        assert_eq!(data, buf);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_self_test() {
+        let plugin = |path: &str| Plugin {
+            name: "foo".into(),
+            path: path.into(),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::stdout),
+            ..Default::default()
+        };
+        let dir = env::temp_dir();
+        assert_eq!(Ok(()), self_test("foo", &plugin("/bin/cat"), &dir));
+        assert!(self_test("foo", &plugin("/bin/false"), &dir).is_err());
+        let err = self_test("foo", &plugin("/nonexistent"), &dir).unwrap_err();
+        assert!(err.starts_with("Failed to spawn plugin"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_extra_outputs() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "echo out; echo report > $REPORT".into()]),
+            output: Some(OutputType::stdout),
+            outputs: Some(vec![ExtraOutput {
+                output_type: OutputType::file,
+                var: "REPORT".into(),
+            }]),
+            ..Default::default()
+        };
+        let records = run(&factory, task(&plugin, b""));
+        let mut data = records
+            .iter()
+            .filter_map(|x| x.get("data")?.as_str())
+            .collect::<Vec<_>>();
+        data.sort();
+        assert_eq!(vec!["out", "report"], data);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_keep_temp() {
+        let mut factory = InputFactory::new(false);
+        factory.keep_temp = true;
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/cat".into(),
+            args: Some(vec!["$INPUT".into()]),
+            output: Some(OutputType::stdout),
+            scratch_dir: Some(true),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"foobar");
+        let input_path = task.plugin.input_path.file().unwrap().clone();
+        let scratch_dir = task.plugin.scratch_dir.clone().unwrap();
+        run_task(drop, drop, &factory, task).unwrap();
+        assert_eq!(b"foobar", fs::read(&input_path).unwrap().as_slice());
+        assert!(scratch_dir.exists());
+        fs::remove_file(input_path).unwrap();
+        fs::remove_dir(scratch_dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_output_kind() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "mkdir $OUTPUT".into()]),
+            output: Some(OutputType::file),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"");
+        let output_path = match &task.plugin.output_path {
+            OutputPath::File(path) => path.clone(),
+            _ => unreachable!(),
+        };
+        let records = run(&factory, task);
+        assert_eq!(Some(&Value::from("error")), records[0].get("level"));
+        assert_eq!(1, factory.stats.plugin_errors.load(Ordering::Relaxed));
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_output_kind_extra() {
+        let factory = InputFactory::new(false);
+        let extra = |var: &str| ExtraOutput {
+            output_type: OutputType::file,
+            var: var.into(),
+        };
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec![
+                "-c".into(),
+                "mkdir $OUTPUT; echo report > $REPORT; mkdir $LOGS".into(),
+            ]),
+            output: Some(OutputType::file),
+            outputs: Some(vec![extra("REPORT"), extra("LOGS")]),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"");
+        let paths = task
+            .plugin
+            .output_paths()
+            .filter_map(OutputPath::path)
+            .cloned()
+            .collect::<Vec<_>>();
+        let records = run(&factory, task);
+        assert_eq!(1, records.len());
+        let msg = records[0].get("error").and_then(Value::as_str).unwrap();
+        assert_eq!(2, msg.matches("Plugin wrote a dir").count(), "{}", msg);
+        assert_eq!(1, factory.stats.plugin_errors.load(Ordering::Relaxed));
+        assert_eq!(3, paths.len());
+        assert!(paths.iter().all(|x| !x.exists()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_filter() {
+        let run = |filter: &str| {
+            let factory = InputFactory::new(false);
+            let plugin = Plugin {
+                name: "foo".into(),
+                path: "/bin/cat".into(),
+                args: Some(vec!["$INPUT".into()]),
+                output: Some(OutputType::stdout),
+                filter: Some(Filter {
+                    path: "/bin/sh".into(),
+                    args: Some(vec!["-c".into(), filter.into(), "$INPUT".into()]),
+                }),
+                ..Default::default()
+            };
+            run(&factory, task(&plugin, b"foobar")).remove(0)
+        };
+        let kept = run("grep -q foo \"$0\"");
+        assert_eq!(Some(&Value::from("foobar")), kept.get("data"));
+        let dropped = run("grep -q baz \"$0\"");
+        assert_eq!(Some(&Value::from("filtered")), dropped.get("reason"));
+    }
+
+    #[test]
+    fn test_run_task_spawn_error() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/nonexistent".into(),
+            output: Some(OutputType::dir),
+            retries: Some(2),
+            retry_delay_ms: Some(1),
+            ..Default::default()
+        };
+        let task = task(&plugin, b"foobar");
+        let input_path = task.plugin.input_path.file().unwrap().clone();
+        let output_path = task.plugin.output_path.dir().unwrap().clone();
+        let records = run(&factory, task);
+        assert!(!input_path.exists());
+        assert!(!output_path.exists());
+        assert_eq!(1, records.len());
+        assert!(records[0].get("error").is_some());
+        assert_eq!(Some(&Value::from("error")), records[0].get("level"));
+        assert_eq!(Some(&Value::from("foo")), records[0].get("plugin"));
+        assert_eq!(1, factory.stats.plugin_errors.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_hashing_reader() {
+        let mut reader = HashingReader::new(Cursor::new(b"foo").chain(Cursor::new(b"bar")));
+        let mut buf = Vec::new();
+        io::copy(&mut reader, &mut buf).unwrap();
+        assert_eq!(b"foobar", buf.as_slice());
+        assert_eq!(
+            "c3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2",
+            reader.digest()
+        );
+    }
+
+    #[test]
+    fn test_hashing_reader_limit() {
+        let mut reader =
+            HashingReader::with_limit(Cursor::new(b"foo").chain(Cursor::new(b"bar")), Some(4));
+        let mut buf = Vec::new();
+        io::copy(&mut reader, &mut buf).unwrap();
+        assert_eq!(b"foobar", buf.as_slice());
+        assert_eq!(6, reader.bytes_read());
+        assert_eq!(4, reader.hashed_bytes());
+        assert_eq!(format!("{:x}", Sha256::digest(b"foob")), reader.digest());
+    }
+
+    // The head read for detection is chained back in front of the rest, so the digest in
+    // the container record covers the whole input
+    #[test]
+    #[cfg(unix)]
+    fn test_hash_sniffed_input() {
+        let config: Config = serde_yaml::from_str(
+            "text: {header: {regex: ^.}, plugin: {name: unpack, path: /bin/sh, \
+             args: [-c, cat > a], input: stdin, output: dir, unpacker: true, \
+             emit_container: true}}",
+        )
+        .unwrap();
+        let pre_processor = PreProcessor::new(&config, DetectionOrder::best, env::temp_dir());
+        let data = (0..SNIFF_SIZE as usize * 3 + 7)
+            .map(|x| (x % 251) as u8 | 1)
+            .collect::<Vec<_>>();
+        let ppi = pre_processor
+            .pre_process(TaskId::new(0), "".into(), "".into(), None, None, &data[..])
+            .unwrap()
+            .ok()
+            .unwrap();
+        let output_dir = ppi.plugin.output_path.dir().unwrap().clone();
+        let (records, _) = run_inputs(&InputFactory::new(false), ppi);
+        fs::remove_dir_all(output_dir).unwrap();
+        let container = records.iter().find(|x| x.get("container").is_some());
+        assert_eq!(
+            Some(&Value::from(format!("{:x}", Sha256::digest(&data)))),
+            container.unwrap().get("sha256")
+        );
+    }
+
+    #[test]
+    fn test_stats_summary() {
+        let stats = Stats::default();
+        stats.inputs.fetch_add(3, Ordering::Relaxed);
+        let ppi = |t: &str| PreProcessedInput {
+            task_id: TaskId::new(0),
+            item_path: "".into(),
+            origin: "".into(),
+            item_type: t.into(),
+            detector_rule: None,
+            binary: false,
+            mtime: None,
+            tags: Vec::new(),
+            plugin: Plugin::default().prep(None, &[], &env::temp_dir()).unwrap(),
+            data: (),
+        };
+        let skipped = RefCell::new(Vec::new());
+        let output_cb = |x: Output| skipped.borrow_mut().push(x);
+        assert!(stats.count_type(Ok(ppi("foo")), &output_cb).is_some());
+        assert!(stats.count_type(Ok(ppi("foo")), &output_cb).is_some());
+        let data = OutputData::Skipped(SkipReason::UnknownType);
+        let output = Output::new(TaskId::new(1), "", "".into(), "", "", data);
+        assert!(stats
+            .count_type::<(), _>(Err(Some(output)), &output_cb)
+            .is_none());
+        let data = OutputData::Skipped(SkipReason::Empty);
+        let output = Output::new(TaskId::new(2), "", "".into(), "", "", data);
+        assert!(stats
+            .count_type::<(), _>(Err(Some(output)), &output_cb)
+            .is_none());
+        assert!(stats.count_type::<(), _>(Err(None), &output_cb).is_none());
+        assert_eq!(2, skipped.borrow().len());
+        stats.plugin("bar").tasks.fetch_add(2, Ordering::Relaxed);
+        stats.plugin("bar").bytes.fetch_add(10, Ordering::Relaxed);
+        let summary = stats.summary(Duration::from_millis(5));
+        assert_eq!(3, summary["inputs"]);
+        assert_eq!(2, summary["types"]["foo"]);
+        assert_eq!(1, summary["unknown"]);
+        assert_eq!(2, summary["empty"]);
+        assert_eq!(0, summary["throttled"]);
+        assert_eq!(0, summary["plugin_errors"]);
+        assert_eq!(
+            json!({"tasks": 2, "errors": 0, "bytes": 10}),
+            summary["plugins"]["bar"]
+        );
+        assert_eq!(5, summary["elapsed_ms"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_budget() {
+        let mut factory = InputFactory::new(false);
+        factory.budget = Arc::new(OutputBudget::new(Some(10), None));
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "echo foo > a; echo barbaz > b".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::dir),
+            unpacker: Some(true),
+            ..Default::default()
+        };
+        let task = PreProcessedInput {
+            origin: "/foo/bar".into(),
+            ..task(&plugin, b"")
+        };
+        let output_dir = task.plugin.output_path.dir().unwrap().clone();
+        let (records, children) = run_inputs(&factory, task);
+        assert!(
+            children.is_empty(),
+            "unpacked output over budget was scheduled"
+        );
+        assert!(!output_dir.exists());
+        assert_eq!(Some(&Value::from(11)), records[0].get("unpacked_bytes"));
+        assert_eq!(None, factory.budget.charge(Path::new("/foo/baz"), 10));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_budget_stdout() {
+        let mut factory = InputFactory::new(false);
+        factory.budget = Arc::new(OutputBudget::new(Some(100_000), None));
+        let config: Config = serde_yaml::from_str(
+            "text: {header: {regex: ^.}, plugin: {name: cat, path: /bin/cat, \
+             input: stdin, output: stdout}}",
+        )
+        .unwrap();
+        let pre_processor = PreProcessor::new(&config, DetectionOrder::best, env::temp_dir());
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "yes foo | head -c 10000000".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::stdout),
+            unpacker: Some(true),
+            ..Default::default()
+        };
+        let task = PreProcessedInput {
+            origin: "/foo/bar".into(),
+            ..task(&plugin, b"")
+        };
+        let records = run_nested(&factory, &pre_processor, task);
+        let bomb = records
+            .iter()
+            .find_map(|x| x.get("unpacked_bytes"))
+            .and_then(Value::as_u64)
+            .expect("no bomb record for the stdout over budget");
+        assert!(bomb > 100_000 && bomb < 10_000_000, "{}", bomb);
+        assert!(records.iter().all(|x| x.get("error").is_none()));
+        assert_eq!(0, factory.stats.plugin_errors.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_budget_used_up() {
+        let mut factory = InputFactory::new(false);
+        factory.budget = Arc::new(OutputBudget::new(Some(10), None));
+        factory.budget.charge(Path::new("/foo/bar"), 20);
+        let plugin = Plugin {
+            unpacker: Some(true),
+            ..cat()
+        };
+        let task = PreProcessedInput {
+            origin: "/foo/bar".into(),
+            ..task(&plugin, b"foo")
+        };
+        let (records, children) = run_inputs(&factory, task);
+        assert!(children.is_empty());
+        assert_eq!(1, records.len());
+        assert_eq!(Some(&Value::from(20)), records[0].get("unpacked_bytes"));
+        assert_eq!(0, factory.stats.plugin_errors.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_sniff_timeout() {
+        let factory = InputFactory::new(false);
+        let mut pre_processor =
+            PreProcessor::new(&Config::new(), DetectionOrder::best, env::temp_dir());
+        pre_processor.sniff_timeout = Some(Duration::from_millis(100));
+        // Holds its stdout open without writing to it
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "exec sleep 30".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::stdout),
+            unpacker: Some(true),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let records = run_nested(&factory, &pre_processor, task(&plugin, b""));
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the unpacker wasn't killed"
+        );
+        assert!(records
+            .iter()
+            .any(|x| x.get("error")
+                == Some(&Value::from("Timed out reading the start of the input"))));
+    }
+
+    #[test]
+    fn test_output_budget_finish() {
+        let budget = OutputBudget::new(Some(10), None);
+        let origin = Path::new("/foo/bar");
+        budget.enter(origin);
+        budget.enter(origin);
+        assert_eq!(None, budget.charge(origin, 8));
+        budget.finish(origin);
+        assert_eq!(Some(16), budget.charge(origin, 8));
+        budget.finish(origin);
+        assert!(budget.used.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_output_budget_ratio() {
+        let budget = OutputBudget::new(None, Some(2));
+        let origin = plugin::gen_path(&env::temp_dir());
+        fs::write(&origin, b"0123456789").unwrap();
+        budget.enter(&origin);
+        // The size is read once, on enter
+        fs::remove_file(&origin).unwrap();
+        assert_eq!(None, budget.charge(&origin, 20));
+        assert_eq!(Some(21), budget.charge(&origin, 1));
+        // Without a size the ratio doesn't apply
+        let origin = Path::new("/foo/bar");
+        budget.enter(origin);
+        assert_eq!(None, budget.charge(origin, 1000));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_task_stage() {
+        let factory = InputFactory::new(false);
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "echo foo > a".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::dir),
+            unpacker: Some(true),
+            ..Default::default()
+        };
+        let task = PreProcessedInput {
+            item_path: "x".into(),
+            item_type: "tar".into(),
+            ..task(&plugin, b"")
+        };
+        let output_dir = task.plugin.output_path.dir().unwrap().clone();
+        let (records, children) = run_inputs(&factory, task);
+        fs::remove_dir_all(output_dir).unwrap();
+        let children = children
+            .into_iter()
+            .map(|x| x.item_path)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![PathBuf::from("x/a")], children);
+        assert_eq!(2, records.len());
+        let stage = records.iter().find(|x| x.get("stage").is_some()).unwrap();
+        assert_eq!(Some(&Value::from(true)), stage.get("stage"));
+        assert_eq!(Some(&Value::from("tar")), stage.get("type"));
+        assert_eq!(Some(&Value::from("foo")), stage.get("plugin"));
+        let count = records
+            .iter()
+            .find(|x| x.get("children").is_some())
+            .unwrap();
+        assert_eq!(Some(&Value::from(1)), count.get("children"));
+        assert_eq!(Some(&Value::from(0)), count.get("task_id"));
+    }
+
+    #[test]
+    fn test_check_produces() {
+        let parent = Plugin {
+            name: "unzip".into(),
+            produces: Some(vec!["text".into()]),
+            ..Default::default()
+        };
+        let child = Plugin {
+            name: "foo".into(),
+            path: "/bin/true".into(),
+            output: Some(OutputType::stdout),
+            ..Default::default()
+        };
+        let check = |item_type: &str| {
+            let ppi = PreProcessedInput {
+                item_type: item_type.into(),
+                ..task(&child, b"")
+            };
+            let cur = SharedCursor::new();
+            check_produces(Some(&parent), &ppi, |x| {
+                x.handle(&OutputContext::default(), &mut cur.clone())
+                    .unwrap()
+            });
+            cur.into_inner()
+        };
+        assert!(check("text").is_empty());
+        let result: Value = serde_json::from_slice(&check("elf")).unwrap();
+        assert_eq!(Some(&Value::from("unzip")), result.get("plugin"));
+        assert_eq!(Some(&Value::from(true)), result.get("unexpected_type"));
+    }
+
+    #[test]
+    fn test_skip_self_nested() {
+        let plugin = Plugin {
+            name: "gunzip".into(),
+            path: "/bin/true".into(),
+            output: Some(OutputType::stdout),
+            allow_self_nesting: Some(false),
+            ..Default::default()
+        };
+        let check = |parent: &Plugin, item_type: &str| {
+            let ppi = PreProcessedInput {
+                item_type: item_type.into(),
+                ..task(&plugin, b"")
+            };
+            let cur = SharedCursor::new();
+            let skipped = skip_self_nested(Some(&"gz".into()), Some(parent), &ppi, |x| {
+                x.handle(&OutputContext::default(), &mut cur.clone())
+                    .unwrap()
+            })
+            .unwrap();
+            (skipped, cur.into_inner())
+        };
+        let (skipped, out) = check(&plugin, "gz");
+        assert!(skipped);
+        let result: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(Some(&Value::from("self_nested")), result.get("reason"));
+        assert!(!check(&plugin, "tar").0);
+        let allowed = Plugin {
+            allow_self_nesting: None,
+            ..plugin.clone()
+        };
+        assert!(!check(&allowed, "gz").0);
+    }
+
+    #[test]
+    fn test_check_duplicate() {
+        let factory = InputFactory::new(true);
+        let paths = [gen_path(&env::temp_dir()), gen_path(&env::temp_dir())];
+        for path in paths.iter() {
+            fs::write(path, b"foobar").unwrap();
+        }
+        let first = factory.check_duplicate(&paths[0]).unwrap();
+        let second = factory.check_duplicate(&paths[1]).unwrap();
+        for path in paths.iter() {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(None, first);
+        assert_eq!(
+            Some("c3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2".into()),
+            second
+        );
+    }
+
+    #[test]
+    fn test_nested_path() {
+        let mut factory = InputFactory::new(false);
+        assert_eq!(
+            PathBuf::from("a.zip/b/c"),
+            factory.nested_path(Path::new("a.zip"), "b/c".into())
+        );
+        factory.container_delimiter = Some("!".into());
+        assert_eq!(
+            PathBuf::from("a.zip!b/c"),
+            factory.nested_path(Path::new("a.zip"), "b/c".into())
+        );
+        assert_eq!(
+            PathBuf::from("b/c"),
+            factory.nested_path(Path::new(""), "b/c".into())
+        );
+    }
+
+    // A task of the plugin with the data as its input, prepped in the temp dir. Other fields
+    // can be set with struct update syntax.
+    fn task(plugin: &Plugin, data: &[u8]) -> PreProcessedInput<Cursor<Vec<u8>>> {
+        PreProcessedInput {
+            task_id: TaskId::new(0),
+            item_path: "".into(),
+            origin: "".into(),
+            item_type: "".into(),
+            detector_rule: None,
+            binary: false,
+            mtime: None,
+            tags: Vec::new(),
+            plugin: plugin.prep(None, &[], &env::temp_dir()).unwrap(),
+            data: Cursor::new(data.to_vec()),
+        }
+    }
+
+    fn cat() -> Plugin {
+        Plugin {
+            name: "foo".into(),
+            path: "/bin/cat".into(),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::stdout),
+            ..Default::default()
+        }
+    }
+
+    fn run(factory: &InputFactory, task: PreProcessedInput<Cursor<Vec<u8>>>) -> Vec<Value> {
+        run_inputs(factory, task).0
+    }
+
+    // Runs the task and returns its records and the inputs it scheduled. Outputs are handled
+    // on their own threads like in the output pool, so the records aren't in order.
+    fn run_inputs<R: Read + Send>(
+        factory: &InputFactory,
+        task: PreProcessedInput<R>,
+    ) -> (Vec<Value>, Vec<Input>) {
+        let inputs = Mutex::new(Vec::new());
+        let cur = SharedCursor::new();
+        thread::scope(|scope| {
+            let readers = Mutex::new(Vec::new());
+            let output_cb = |x: Output| {
+                let mut cur = cur.clone();
+                let context = OutputContext::new("job");
+                let reader = scope.spawn(move || x.handle(&context, &mut cur));
+                readers.lock().unwrap().push(reader);
+            };
+            run_task(|x| inputs.lock().unwrap().push(x), output_cb, factory, task).unwrap();
+            for reader in readers.into_inner().unwrap() {
+                reader.join().unwrap().unwrap();
+            }
+        });
+        let records = serde_json::Deserializer::from_slice(&cur.into_inner())
+            .into_iter::<Value>()
+            .map(|x| x.unwrap())
+            .collect();
+        (records, inputs.into_inner().unwrap())
+    }
+
+    // Also handles the inputs unpacked from stdout, though not the ones nested in those
+    fn run_nested(
+        factory: &InputFactory,
+        pre_processor: &PreProcessor,
+        task: PreProcessedInput<Cursor<Vec<u8>>>,
+    ) -> Vec<Value> {
+        let cur = SharedCursor::new();
+        let records = &cur;
+        thread::scope(|scope| {
+            let output_cb = move |x: Output| {
+                let mut cur = records.clone();
+                scope.spawn(move || x.handle(&OutputContext::new("job"), &mut cur).unwrap());
+            };
+            let input_cb = move |x: Input| {
+                scope.spawn(move || x.handle(factory, pre_processor, drop, output_cb).unwrap());
+            };
+            run_task(input_cb, output_cb, factory, task).unwrap();
+        });
+        serde_json::Deserializer::from_slice(&cur.into_inner())
+            .into_iter::<Value>()
+            .map(|x| x.unwrap())
+            .collect()
     }
 
     #[derive(Clone)]
@@ -1,125 +1,1044 @@
-#![feature(command_access, thread_id_value)]
-
+use std::collections::HashSet;
 use std::env;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, Stdout, Write};
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, Read, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use env_logger::Builder;
+use flate2::read::GzDecoder;
 use getopts::Options;
-use log::debug;
-use serde_yaml::from_reader;
+use log::{debug, error, info, warn, LevelFilter};
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_json::json;
+use serde_yaml::from_str;
 
-use crate::input::InputData;
-use crate::plugin::Config;
-use crate::thread::Pool;
+use crate::input::{InputData, InputFactory, OutputBudget};
+use crate::output::{OutputContext, OutputData, SkipReason};
+use crate::plugin::{ConfigFile, Header};
+use crate::pre_process::{EmptyInputs, PreProcessor, EMPTY_TYPE};
+use crate::rate::{RateLimiter, ThrottleAction};
+use crate::sink::{GzipSink, HttpSink, TeeErrors, TeeSink};
+use crate::thread::{ErrorLimit, Pool};
 
 mod input;
 mod output;
 mod plugin;
 mod pre_process;
+mod rate;
+mod sink;
 mod thread;
 mod walk;
 
+static PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
 fn main() {
-    init_logger();
     let opts = set_opts();
     let args: Vec<String> = env::args().collect();
     let params = read_params(&opts, &args);
+    init_logger(params.log_format, params.log_level);
     if params.help {
         print!("{}", opts.usage("Usage: factory [options]"));
-    } else if let Some(cpath) = params.config {
-        let cfile = File::open(cpath).unwrap();
-        let conf: Config = from_reader(cfile).unwrap();
-        debug!("Config: {:?}", conf);
-        execute(params.input, conf, Output(io::stdout())).unwrap();
+    } else if let Some(cpath) = &params.config {
+        let config_stdin = cpath.as_os_str() == "-";
+        // Stdin can't be both the config and the input
+        if config_stdin
+            && !params.selftest
+            && !params.list_types
+            && params.input.is_empty()
+            && params.input_list.is_none()
+        {
+            error!("Give the inputs with -i or --input-list when the config is read from stdin");
+            std::process::exit(1);
+        }
+        let mut cfile = String::new();
+        open_config(cpath)
+            .unwrap()
+            .read_to_string(&mut cfile)
+            .unwrap();
+        let duplicates = duplicate_keys(&cfile).unwrap();
+        if !duplicates.is_empty() {
+            error!(
+                "Keys defined more than once in the config: {}",
+                duplicates.join(", ")
+            );
+            std::process::exit(1);
+        }
+        let mut config: ConfigFile = from_str(&cfile).unwrap();
+        let conf = &mut config.types;
+        // Relative paths in a config from stdin are relative to the current dir
+        let base_dir = if config_stdin {
+            env::current_dir().unwrap()
+        } else {
+            let config_path = fs::canonicalize(cpath).unwrap();
+            config_path.parent().unwrap().to_path_buf()
+        };
+        for settings in conf.values_mut() {
+            settings.plugin.resolve_path(&base_dir);
+        }
+        for file_type in &params.disable {
+            match conf.get_mut(file_type) {
+                Some(settings) => settings.plugin.enabled = Some(false),
+                None => warn!("Type to disable not included in config: {}", file_type),
+            }
+        }
+        for settings in conf.values_mut() {
+            let args = settings.plugin.args.get_or_insert_with(Vec::new);
+            args.extend(params.plugin_args.iter().cloned());
+        }
+        debug!("Config: {:?}", config);
+        if params.list_types {
+            list_types(&config, io::stdout()).unwrap();
+            return;
+        }
+        if params.selftest {
+            if !selftest(&params, &config).unwrap() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        if params.output_compress.is_some() && !to_stdout(&params) {
+            warn!("Batches posted to an output URL are not compressed");
+        }
+        // The output threads hold on to their sinks, so the gzip streams are finished here
+        let mut gzip_sinks = Vec::new();
+        let mut http_sinks = Vec::new();
+        let completed = match params.output.as_slice() {
+            [url] if url != STDOUT => {
+                let sink = HttpSink::new(url).unwrap();
+                http_sinks.push(sink.clone());
+                execute(&params, config, sink).unwrap()
+            }
+            [] | [_] => match params.output_compress {
+                Some(OutputCompression::Gzip) => {
+                    let sink = GzipSink::new(Output(io::stdout()));
+                    gzip_sinks.push(sink.clone());
+                    execute(&params, config, sink).unwrap()
+                }
+                None => execute(&params, config, Output(io::stdout())).unwrap(),
+            },
+            outputs => {
+                let mut sink = TeeSink::new(params.output_errors);
+                for output in outputs {
+                    if output != STDOUT {
+                        let http = HttpSink::new(output).unwrap();
+                        http_sinks.push(http.clone());
+                        sink.add(http);
+                        continue;
+                    }
+                    match params.output_compress {
+                        Some(OutputCompression::Gzip) => {
+                            let gzip = GzipSink::new(Output(io::stdout()));
+                            gzip_sinks.push(gzip.clone());
+                            sink.add(gzip)
+                        }
+                        None => sink.add(Output(io::stdout())),
+                    }
+                }
+                execute(&params, config, sink).unwrap()
+            }
+        };
+        for sink in gzip_sinks {
+            sink.finish().unwrap();
+        }
+        let dropped = http_sinks.iter().map(HttpSink::dropped).sum::<usize>();
+        if dropped > 0 {
+            error!("Dropped {} records that failed to post", dropped);
+            std::process::exit(1);
+        }
+        if !completed {
+            std::process::exit(2);
+        }
     } else {
         print!("{}", opts.usage("Usage: factory [options]"));
     }
 }
 
-fn execute<E>(input: Option<PathBuf>, config: Config, exit: E) -> io::Result<()>
+// Returns false if the run was cut short by --max-errors or --deadline
+fn execute<E>(params: &Params, config: ConfigFile, exit: E) -> io::Result<bool>
 where
     E: Write + Clone + Send + 'static,
 {
+    if config.buffer_size == Some(0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "buffer_size must be above 0",
+        ));
+    }
+    let empty_type = config.types.get(EMPTY_TYPE);
+    if params.empty_inputs == EmptyInputs::Route
+        && !empty_type.is_some_and(|x| x.plugin.is_enabled())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--empty-inputs route needs an enabled empty type in the config",
+        ));
+    }
+    for settings in config.types.values().filter(|x| x.plugin.is_enabled()) {
+        settings.plugin.validate()?;
+    }
+    if params.max_spawn_rate == Some(0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--max-spawn-rate must be above 0",
+        ));
+    }
+    if params.throttle_wait.is_some() && params.max_spawn_rate.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--throttle-wait needs --max-spawn-rate",
+        ));
+    }
+    let start = Instant::now();
     let cpus = num_cpus::get();
-    let mut pool = Pool::new(config, exit);
-    pool.add_input_threads(cpus);
-    pool.add_output_threads(cpus * 2);
-    let input_path = match input {
+    let mut sink = exit.clone();
+    let cwd = env::current_dir()?;
+    let input_paths = params.input.iter().map(|x| cwd.join(x)).collect::<Vec<_>>();
+    let input_list = match &params.input_list {
         Some(p) => {
-            let mut path = env::current_dir()?;
-            path.push(p);
-            Some(path)
+            let lines = read_input_list(BufReader::new(File::open(p)?))?;
+            Some(
+                lines
+                    .into_iter()
+                    .map(|x| (cwd.join(&x), x))
+                    .collect::<Vec<_>>(),
+            )
         }
         None => None,
     };
-    let working_dir = plugin::gen_path()?;
-    fs::create_dir(&working_dir).unwrap();
-    env::set_current_dir(&working_dir)?;
-    if let Some(path) = input_path {
-        if path.is_dir() {
-            walk::walk_dir(path, "".into(), |p, ip| {
-                let inp = pool.factory.new_input(ip, InputData::File(p, false));
-                pool.input_sender.send(inp).unwrap();
-            })?;
-        } else {
-            pool.input_sender
-                .send(pool.factory.new_input("", InputData::File(path, false)))
-                .unwrap();
-        }
-    } else {
-        pool.input_sender
-            .send(pool.factory.new_input("", InputData::Stdin(io::stdin())))
+    let temp_dir = match &params.temp_dir {
+        Some(dir) => cwd.join(dir),
+        None => env::temp_dir(),
+    };
+    let working_dir = plugin::create_unique_dir(&temp_dir)?;
+    info!("Working dir: {:?}", working_dir);
+    let order = config.detection_order.unwrap_or_default();
+    let mut pre_processor = PreProcessor::new(&config.types, order, working_dir.clone());
+    pre_processor.stdin_type = params.stdin_type.clone();
+    pre_processor.sniff_timeout = params.sniff_timeout.map(Duration::from_secs);
+    pre_processor.empty_inputs = params.empty_inputs;
+    let mut factory = InputFactory::new(params.dedup);
+    let budget = OutputBudget::new(params.max_unpacked_bytes, params.max_unpacked_ratio);
+    factory.budget = Arc::new(budget);
+    factory.spawn_limiter = params.max_spawn_rate.map(RateLimiter::new);
+    factory.throttle_wait = params.throttle_wait.map(Duration::from_secs);
+    factory.throttle_action = params.throttle_action;
+    factory.container_delimiter = config.container_delimiter.clone();
+    factory.hash_bytes = config.hash_bytes;
+    factory.keep_temp = params.keep_temp;
+    // Listed in the summary even if they never run
+    for settings in config.types.values().filter(|x| x.plugin.is_enabled()) {
+        factory.stats.plugin(&settings.plugin.name);
+    }
+    let progress = Arc::new(AtomicUsize::new(0));
+    if params.progress {
+        factory.progress = Some(progress.clone());
+    }
+    let job_id = params.job_id.clone().unwrap_or_else(gen_job_id);
+    debug!("Job id: {}", job_id);
+    let mut context = OutputContext::new(job_id);
+    context.buffer_size = config.buffer_size.unwrap_or(output::BUFSIZE);
+    context.thread_ids = params.thread_ids;
+    context.fields = params.output_fields.clone();
+    context.omit_fields = params.omit_fields.clone();
+    // Batches posted to an output URL are always NDJSON, so is stdout when teed with one
+    if params.output.iter().all(|x| x == STDOUT) {
+        context.record_sep = params.record_sep.0;
+    }
+    let mut pool = Pool::new(pre_processor, factory, context, exit);
+    pool.errors = Arc::new(ErrorLimit::new(params.max_errors));
+    if let Some(secs) = params.deadline {
+        let shutdown = pool.shutdown.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(secs));
+            warn!(
+                "Reached the deadline of {}s, skipping the remaining inputs",
+                secs
+            );
+            shutdown.trigger();
+        });
+    }
+    if let Some(size) = params.walk_ahead {
+        pool.limit_walk_queue(size);
+    }
+    pool.add_input_threads(cpus);
+    pool.add_unpack_threads(params.unpack_threads.unwrap_or(cpus));
+    pool.add_nested_unpack_threads(params.nested_unpack_threads.unwrap_or(cpus));
+    pool.add_output_threads(cpus * 2);
+    if params.progress {
+        let queue_depth = pool.queue_depth();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(PROGRESS_INTERVAL);
+            info!(
+                "Progress: {} tasks finished, {} inputs queued",
+                progress.load(Ordering::Relaxed),
+                queue_depth.get()
+            );
+        });
+    }
+    if input_paths.is_empty() && input_list.is_none() {
+        pool.walk_sender
+            .send(
+                pool.factory
+                    .new_input("", "".into(), InputData::Stdin(io::stdin())),
+            )
             .unwrap();
     }
+    for path in input_paths {
+        if pool.errors.exceeded() || pool.shutdown.triggered() {
+            break;
+        }
+        send_path(&pool, params, path, "".into())?;
+    }
+    for (path, item_path) in input_list.into_iter().flatten() {
+        if pool.errors.exceeded() || pool.shutdown.triggered() {
+            break;
+        }
+        send_path(&pool, params, path, item_path.into())?;
+    }
+    pool.join().unwrap();
+    let mut summary = pool.factory.stats.summary(start.elapsed());
+    if pool.shutdown.triggered() {
+        summary["deadline_reached"] = true.into();
+    }
+    let output = output::Output::new(
+        pool.factory.new_task_id(),
+        "",
+        "".into(),
+        "",
+        "",
+        OutputData::Summary(summary),
+    );
+    pool.output_sender.send(output).unwrap();
     pool.join().unwrap();
-    env::set_current_dir(&working_dir.parent().unwrap())?;
-    fs::remove_dir_all(working_dir).unwrap();
+    sink.flush()?;
+    if params.keep_temp {
+        info!("Keeping working dir {:?}", working_dir);
+    } else {
+        fs::remove_dir_all(working_dir).unwrap();
+    }
+    Ok(!pool.errors.exceeded() && !pool.shutdown.triggered())
+}
+
+// Runs each enabled plugin on its test input and prints a table of the results,
+// returns false if any failed
+fn selftest(params: &Params, config: &ConfigFile) -> io::Result<bool> {
+    let temp_dir = match &params.temp_dir {
+        Some(dir) => env::current_dir()?.join(dir),
+        None => env::temp_dir(),
+    };
+    let working_dir = plugin::create_unique_dir(&temp_dir)?;
+    let mut types = config
+        .types
+        .iter()
+        .filter(|x| x.1.plugin.is_enabled())
+        .collect::<Vec<_>>();
+    types.sort_by_key(|x| x.0);
+    let mut passed = true;
+    let mut stdout = io::stdout();
+    for (file_type, settings) in types {
+        let result = match input::self_test(file_type, &settings.plugin, &working_dir) {
+            Ok(()) => "pass".to_string(),
+            Err(msg) => {
+                passed = false;
+                format!("FAIL {}", msg)
+            }
+        };
+        writeln!(
+            stdout,
+            "{:<16} {:<16} {}",
+            file_type, settings.plugin.name, result
+        )?;
+    }
+    if params.keep_temp {
+        info!("Keeping working dir {:?}", working_dir);
+    } else {
+        fs::remove_dir_all(working_dir)?;
+    }
+    Ok(passed)
+}
+
+// Prints a table of the enabled types with their plugin and signatures
+fn list_types<W: Write>(config: &ConfigFile, mut out: W) -> io::Result<()> {
+    let mut types = config
+        .types
+        .iter()
+        .filter(|x| x.1.plugin.is_enabled())
+        .collect::<Vec<_>>();
+    types.sort_by_key(|x| x.0);
+    writeln!(
+        out,
+        "{:<16} {:<16} {:<32} {:<8} SIGNATURES",
+        "TYPE", "PLUGIN", "PATH", "UNPACKER"
+    )?;
+    for (file_type, settings) in types {
+        let headers = settings.headers.iter().map(|x| signature(x, ""));
+        let footers = settings.footers.iter().map(|x| signature(x, "footer "));
+        let signatures = headers.chain(footers).collect::<Vec<_>>();
+        writeln!(
+            out,
+            "{:<16} {:<16} {:<32} {:<8} {}",
+            file_type,
+            settings.plugin.name,
+            settings.plugin.path.display(),
+            if settings.plugin.unpacker.unwrap_or(false) {
+                "yes"
+            } else {
+                "no"
+            },
+            if signatures.is_empty() {
+                "-".to_string()
+            } else {
+                signatures.join("; ")
+            }
+        )?;
+    }
+    Ok(())
+}
+
+// E.g. /^foo/i, hex(50 4B) at 4 or footer /%%EOF/
+fn signature(header: &Header, prefix: &str) -> String {
+    let mut sig = if header.is_hex() {
+        format!("{}hex({})", prefix, header.regex)
+    } else {
+        format!("{}/{}/", prefix, header.regex)
+    };
+    if header.is_case_insensitive() {
+        sig.push('i');
+    }
+    if let Some(offset) = header.offset.filter(|x| *x > 0) {
+        sig.push_str(&format!(" at {}", offset));
+    }
+    sig
+}
+
+fn send_path<E>(
+    pool: &Pool<E>,
+    params: &Params,
+    path: PathBuf,
+    item_path: PathBuf,
+) -> io::Result<()> {
+    if params.no_follow && walk::is_symlink(&path) {
+        send_file(pool, params, path, item_path);
+    } else if path.is_dir() {
+        let follow_links = !params.no_follow;
+        walk::walk_dir(
+            path,
+            item_path,
+            params.skip_hidden,
+            follow_links,
+            |p, ip| send_file(pool, params, p, ip),
+        )?;
+    } else if path.exists() {
+        send_file(pool, params, path, item_path);
+    } else {
+        error!("Input file does not exist {:?}", path);
+        let output = output::Output::new(
+            pool.factory.new_task_id(),
+            item_path,
+            path,
+            "",
+            "",
+            OutputData::Error("Input file does not exist".into()),
+        );
+        pool.output_sender.send(output).unwrap();
+    }
     Ok(())
 }
 
+fn send_file<E>(pool: &Pool<E>, params: &Params, path: PathBuf, item_path: PathBuf) {
+    if pool.shutdown.triggered() {
+        return;
+    }
+    if params.no_follow && walk::is_symlink(&path) {
+        let target = fs::read_link(&path).unwrap_or_default();
+        info!("Not following symlink {:?} to {:?}", path, target);
+        let output = output::Output::new(
+            pool.factory.new_task_id(),
+            item_path,
+            path,
+            "",
+            "",
+            OutputData::Symlink(target),
+        );
+        pool.output_sender.send(output).unwrap();
+        return;
+    }
+    if walk::is_stream(&path) {
+        debug!("Reading {:?} as a stream", path);
+        let input = pool
+            .factory
+            .new_input(item_path, path.clone(), InputData::Pipe(path));
+        pool.walk_sender.send(input).unwrap();
+        return;
+    }
+    let too_large = params.max_file_size.and_then(|max| {
+        let size = fs::metadata(&path).ok()?.len();
+        (size > max).then_some(size)
+    });
+    match too_large {
+        Some(size) => {
+            warn!("Skipping {:?}, size {} is over the maximum", path, size);
+            let output = output::Output::new(
+                pool.factory.new_task_id(),
+                item_path,
+                path,
+                "",
+                "",
+                OutputData::Skipped(SkipReason::TooLarge),
+            );
+            pool.output_sender.send(output).unwrap();
+        }
+        _ => {
+            let input =
+                pool.factory
+                    .new_input(item_path, path.clone(), InputData::File(path, false));
+            pool.walk_sender.send(input).unwrap();
+        }
+    }
+}
+
+// Configs ending in .gz or .zst are decompressed, - is stdin
+fn open_config(path: &Path) -> io::Result<Box<dyn Read>> {
+    if path.as_os_str() == "-" {
+        return Ok(Box::new(io::stdin()));
+    }
+    let file = BufReader::new(File::open(path)?);
+    Ok(match path.extension().and_then(|x| x.to_str()) {
+        Some("gz") => Box::new(GzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(file),
+    })
+}
+
+// serde_yaml keeps the last value of a duplicate key, so a copy-pasted type would
+// silently replace the first one
+fn duplicate_keys(config: &str) -> Result<Vec<String>, serde_yaml::Error> {
+    struct Keys(Vec<String>);
+
+    impl<'de> Deserialize<'de> for Keys {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Keys, D::Error> {
+            struct KeysVisitor;
+
+            impl<'de> Visitor<'de> for KeysVisitor {
+                type Value = Keys;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a map")
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Keys, A::Error> {
+                    let mut keys = Vec::new();
+                    while let Some(key) = map.next_key()? {
+                        map.next_value::<IgnoredAny>()?;
+                        keys.push(key);
+                    }
+                    Ok(Keys(keys))
+                }
+            }
+
+            deserializer.deserialize_map(KeysVisitor)
+        }
+    }
+
+    let Keys(keys) = from_str(config)?;
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    for key in keys {
+        if !seen.insert(key.clone()) && !duplicates.contains(&key) {
+            duplicates.push(key);
+        }
+    }
+    Ok(duplicates)
+}
+
+// Random (version 4) UUID
+fn gen_job_id() -> String {
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex = b.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// Paths are separated by newlines, blank lines and lines starting with # are skipped
+fn read_input_list<R: BufRead>(reader: R) -> io::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let path = line.trim();
+        if !path.is_empty() && !path.starts_with('#') {
+            paths.push(path.to_string());
+        }
+    }
+    Ok(paths)
+}
+
 fn set_opts() -> Options {
     let mut opts = Options::new();
     opts.optflag("h", "help", "Show this help information.");
-    opts.optopt("c", "config", "Path to the config file (required)", "PATH");
     opts.optopt(
+        "c",
+        "config",
+        "Path to the config file, - to read it from stdin (required)",
+        "PATH",
+    );
+    opts.optmulti(
         "i",
         "input",
-        "Path to the input file (will read from stdin if not specified)",
+        "Path to an input file or dir, can be repeated (will read from stdin if not specified)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "stdin-type",
+        "Type of the data read from stdin, skips detecting it",
+        "TYPE",
+    );
+    opts.optopt(
+        "",
+        "input-list",
+        "Path to a file listing input paths, one per line",
         "PATH",
     );
+    opts.optmulti(
+        "o",
+        "output",
+        "URL to POST the output to as NDJSON batches or - for stdout, can be repeated to \
+         write to all of them (will write to stdout if not specified), exits with status 1 \
+         if records were dropped because they failed to post",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "output-errors",
+        "When writing to more than one output, fail on the first error or disable a failed \
+         output and only fail when all of them did (default: fail-fast)",
+        "fail-fast|best-effort",
+    );
+    opts.optopt(
+        "",
+        "record-sep",
+        "Byte written after each record on stdout, e.g. \\0 or \\x1e (default: \\n)",
+        "SEP",
+    );
+    opts.optopt(
+        "",
+        "output-compress",
+        "Compress the records written to stdout",
+        "gzip",
+    );
+    opts.optopt(
+        "",
+        "output-fields",
+        "Only write these fields of each record, e.g. path,type,data",
+        "FIELD,...",
+    );
+    opts.optopt(
+        "",
+        "omit-fields",
+        "Leave these fields out of each record",
+        "FIELD,...",
+    );
+    opts.optflagmulti(
+        "q",
+        "quiet",
+        "Only log errors, repeat to log nothing (overrides RUST_LOG)",
+    );
+    opts.optflagmulti(
+        "v",
+        "verbose",
+        "Log info, repeat for debug and trace (overrides RUST_LOG)",
+    );
+    opts.optopt(
+        "",
+        "log-format",
+        "Format of the log lines written to stderr (default: text)",
+        "text|json",
+    );
+    opts.optopt(
+        "",
+        "temp-dir",
+        "Dir to create the working dir in (default: $TMPDIR or the system temp dir)",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "keep-temp",
+        "Keep the temp files of tasks and the working dir for inspection",
+    );
+    opts.optflag(
+        "",
+        "thread-ids",
+        "Add the id of the thread that created a task to records with a task_id",
+    );
+    opts.optopt(
+        "",
+        "job-id",
+        "Id added to every record of this run (default: a random UUID)",
+        "STRING",
+    );
+    opts.optflag(
+        "",
+        "skip-hidden",
+        "Skip hidden files and dirs when walking input dirs",
+    );
+    opts.optflag(
+        "",
+        "no-follow",
+        "Report symlinked inputs with their target instead of reading through them",
+    );
+    opts.optopt(
+        "",
+        "max-unpacked-bytes",
+        "Stop unpacking a top-level input once its unpacked output exceeds this size",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-unpacked-ratio",
+        "Stop unpacking a top-level input once its unpacked output exceeds this multiple of its size",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "max-spawn-rate",
+        "Maximum number of plugin processes started per second",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "throttle-wait",
+        "Write a throttled record for a task that waits longer than this for \
+         --max-spawn-rate, then let it wait on or drop it",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "throttle-action",
+        "Whether a throttled task waits for its turn or is dropped (default: wait)",
+        "wait|drop",
+    );
+    opts.optopt(
+        "",
+        "max-errors",
+        "Stop handling inputs after N errors and exit with status 2",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "deadline",
+        "Stop handling inputs after this many seconds, let running tasks finish and exit \
+         with status 2",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "sniff-timeout",
+        "Fail an unpacked input if reading its start from the unpacker takes longer than this",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "empty-inputs",
+        "Skip inputs without data silently, with a record or route them to the type empty \
+         (default: detect)",
+        "skip|record|route",
+    );
+    opts.optopt(
+        "",
+        "max-file-size",
+        "Skip input files larger than this before detecting their type",
+        "BYTES",
+    );
+    opts.optmulti(
+        "",
+        "disable",
+        "Disable the plugin of a type from the config, can be repeated",
+        "TYPE",
+    );
+    opts.optmulti(
+        "",
+        "plugin-arg",
+        "Append an arg to the args of every plugin, after its own args. $INPUT and $OUTPUT \
+         in it are replaced like in the config, can be repeated",
+        "ARG",
+    );
+    opts.optopt(
+        "",
+        "walk-ahead",
+        "Walk input dirs at most N files ahead of the input threads (default: walk them \
+         eagerly, queueing every file)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "unpack-threads",
+        "Number of threads reading the stdout of unpackers (default: number of CPUs)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "nested-unpack-threads",
+        "Number of threads reading the stdout of unpackers nested in the stdout of another, \
+         once they are busy it's read by the thread of the outer unpacker (default: number \
+         of CPUs)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "progress",
+        "Log the number of finished tasks and queued inputs every few seconds (at info level)",
+    );
+    opts.optflag(
+        "",
+        "selftest",
+        "Run each plugin once on its test_input (or a line of text), print whether it \
+         succeeded and exit with status 1 if any failed",
+    );
+    opts.optflag(
+        "",
+        "list-types",
+        "Print the enabled types of the config with their plugin and signatures and exit",
+    );
+    opts.optflag(
+        "",
+        "dedup",
+        "Skip input files with the same content as an already processed file",
+    );
     opts
 }
 
-fn read_params(opts: &Options, args: &Vec<String>) -> Params {
+fn read_params(opts: &Options, args: &[String]) -> Params {
     let matches = opts.parse(&args[1..]).unwrap();
     Params {
         help: matches.opt_present("help"),
         config: matches.opt_get("config").unwrap(),
-        input: matches.opt_get("input").unwrap(),
+        input: matches
+            .opt_strs("input")
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+        input_list: matches.opt_get("input-list").unwrap(),
+        stdin_type: matches.opt_str("stdin-type"),
+        output: matches.opt_strs("output"),
+        output_errors: matches
+            .opt_get_default("output-errors", TeeErrors::FailFast)
+            .unwrap(),
+        temp_dir: matches.opt_get("temp-dir").unwrap(),
+        keep_temp: matches.opt_present("keep-temp"),
+        thread_ids: matches.opt_present("thread-ids"),
+        job_id: matches.opt_str("job-id"),
+        dedup: matches.opt_present("dedup"),
+        selftest: matches.opt_present("selftest"),
+        list_types: matches.opt_present("list-types"),
+        progress: matches.opt_present("progress"),
+        disable: matches.opt_strs("disable"),
+        plugin_args: matches.opt_strs("plugin-arg"),
+        max_unpacked_bytes: matches.opt_get("max-unpacked-bytes").unwrap(),
+        max_unpacked_ratio: matches.opt_get("max-unpacked-ratio").unwrap(),
+        max_spawn_rate: matches.opt_get("max-spawn-rate").unwrap(),
+        throttle_wait: matches.opt_get("throttle-wait").unwrap(),
+        throttle_action: matches
+            .opt_get_default("throttle-action", ThrottleAction::Wait)
+            .unwrap(),
+        max_errors: matches.opt_get("max-errors").unwrap(),
+        deadline: matches.opt_get("deadline").unwrap(),
+        max_file_size: matches.opt_get("max-file-size").unwrap(),
+        sniff_timeout: matches.opt_get("sniff-timeout").unwrap(),
+        empty_inputs: matches
+            .opt_get_default("empty-inputs", EmptyInputs::Detect)
+            .unwrap(),
+        unpack_threads: matches.opt_get("unpack-threads").unwrap(),
+        nested_unpack_threads: matches.opt_get("nested-unpack-threads").unwrap(),
+        walk_ahead: matches.opt_get("walk-ahead").unwrap(),
+        skip_hidden: matches.opt_present("skip-hidden"),
+        no_follow: matches.opt_present("no-follow"),
+        record_sep: matches
+            .opt_get_default("record-sep", RecordSep(output::NEWLINE))
+            .unwrap(),
+        output_compress: matches.opt_get("output-compress").unwrap(),
+        output_fields: matches.opt_str("output-fields").map(|x| split_fields(&x)),
+        omit_fields: matches
+            .opt_str("omit-fields")
+            .map(|x| split_fields(&x))
+            .unwrap_or_default(),
+        log_format: matches
+            .opt_get_default("log-format", LogFormat::Text)
+            .unwrap(),
+        log_level: log_level(matches.opt_count("quiet"), matches.opt_count("verbose")),
     }
 }
 
+fn split_fields(s: &str) -> HashSet<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Each -v raises the level from warn and each -q lowers it, None keeps RUST_LOG
+fn log_level(quiet: usize, verbose: usize) -> Option<LevelFilter> {
+    if quiet == 0 && verbose == 0 {
+        return None;
+    }
+    let levels = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    let level = (2 + verbose).saturating_sub(quiet);
+    Some(levels[level.min(levels.len() - 1)])
+}
+
 struct Params {
     help: bool,
     config: Option<PathBuf>,
-    input: Option<PathBuf>,
+    input: Vec<PathBuf>,
+    input_list: Option<PathBuf>,
+    stdin_type: Option<String>,
+    output: Vec<String>,
+    output_errors: TeeErrors,
+    temp_dir: Option<PathBuf>,
+    keep_temp: bool,
+    thread_ids: bool,
+    job_id: Option<String>,
+    dedup: bool,
+    selftest: bool,
+    list_types: bool,
+    progress: bool,
+    disable: Vec<String>,
+    plugin_args: Vec<String>,
+    max_unpacked_bytes: Option<u64>,
+    max_unpacked_ratio: Option<u64>,
+    max_spawn_rate: Option<u32>,
+    throttle_wait: Option<u64>,
+    throttle_action: ThrottleAction,
+    max_errors: Option<u64>,
+    deadline: Option<u64>,
+    max_file_size: Option<u64>,
+    sniff_timeout: Option<u64>,
+    empty_inputs: EmptyInputs,
+    unpack_threads: Option<usize>,
+    nested_unpack_threads: Option<usize>,
+    walk_ahead: Option<usize>,
+    skip_hidden: bool,
+    no_follow: bool,
+    record_sep: RecordSep,
+    output_compress: Option<OutputCompression>,
+    output_fields: Option<HashSet<String>>,
+    omit_fields: HashSet<String>,
+    log_format: LogFormat,
+    log_level: Option<LevelFilter>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct RecordSep(u8);
+
+impl FromStr for RecordSep {
+    type Err = String;
+
+    // A single ASCII character or one of the escapes \n, \r, \t, \0 and \xHH
+    fn from_str(s: &str) -> Result<RecordSep, String> {
+        let sep = match s {
+            "\\n" => Some(b'\n'),
+            "\\r" => Some(b'\r'),
+            "\\t" => Some(b'\t'),
+            "\\0" => Some(0),
+            _ if s.len() == 1 && s.is_ascii() => Some(s.as_bytes()[0]),
+            _ => s
+                .strip_prefix("\\x")
+                .filter(|x| x.len() == 2)
+                .and_then(|x| u8::from_str_radix(x, 16).ok()),
+        };
+        sep.map(RecordSep)
+            .ok_or_else(|| format!("Invalid record separator: {}", s))
+    }
 }
 
-fn init_logger() {
-    Builder::from_default_env()
-        .format(|buf, record| {
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum OutputCompression {
+    Gzip,
+}
+
+impl FromStr for OutputCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputCompression, String> {
+        match s {
+            "gzip" => Ok(OutputCompression::Gzip),
+            _ => Err(format!("Invalid output compression: {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format: {}", s)),
+        }
+    }
+}
+
+fn init_logger(format: LogFormat, level: Option<LevelFilter>) {
+    let mut builder = Builder::from_default_env();
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
             writeln!(
                 buf,
                 "[{} {} Thread({})] {}",
                 buf.timestamp(),
                 record.level(),
-                std::thread::current().id().as_u64(),
+                thread::current_id(),
                 record.args()
             )
-        })
-        .init();
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "thread": thread::current_id(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder.init();
+}
+
+// Value of --output for writing to stdout
+static STDOUT: &str = "-";
+
+fn to_stdout(params: &Params) -> bool {
+    params.output.is_empty() || params.output.iter().any(|x| x == STDOUT)
 }
 
 struct Output(Stdout);
@@ -141,3 +1060,108 @@ impl Write for Output {
         self.0.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn test_read_input_list() {
+        let list = "/foo/bar\n\n# comment\n  baz/qux \n";
+        assert_eq!(
+            read_input_list(list.as_bytes()).unwrap(),
+            vec!["/foo/bar".to_string(), "baz/qux".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_list_types() {
+        let config: ConfigFile = from_str(
+            "zip: {header: {regex: 50 4B, hex: true, offset: 4}, \
+                   plugin: {name: unzip, path: /usr/bin/unzip, unpacker: true}}\n\
+             pdf: {header: {regex: '^%pdf', case_insensitive: true}, footer: {regex: '%%EOF'}, \
+                   plugin: {name: pdftotext, path: pdftotext}}\n\
+             off: {header: {regex: ^off}, plugin: {name: off, path: off, enabled: false}}",
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        list_types(&config, &mut buf).unwrap();
+        let table = String::from_utf8(buf).unwrap();
+        // Columns are padded, compare the cells
+        let rows = table
+            .lines()
+            .map(|x| x.split_whitespace().collect::<Vec<_>>().join(" "));
+        assert_eq!(
+            vec![
+                "TYPE PLUGIN PATH UNPACKER SIGNATURES",
+                "pdf pdftotext pdftotext no /^%pdf/i; footer /%%EOF/",
+                "zip unzip /usr/bin/unzip yes hex(50 4B) at 4",
+            ],
+            rows.collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_open_config() {
+        let conf = b"foo: bar\n";
+        let path = plugin::gen_path(&env::temp_dir()).with_extension("gz");
+        let mut gz = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        gz.write_all(conf).unwrap();
+        gz.finish().unwrap();
+        let mut buf = Vec::new();
+        open_config(&path).unwrap().read_to_end(&mut buf).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&conf[..], buf.as_slice());
+        let path = path.with_extension("zst");
+        fs::write(&path, zstd::encode_all(&conf[..], 0).unwrap()).unwrap();
+        let mut buf = Vec::new();
+        open_config(&path).unwrap().read_to_end(&mut buf).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&conf[..], buf.as_slice());
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let conf = "a: {x: 1}\nb: 2\na: 3\nb: [4]\nc: 5\na: 6\n";
+        assert_eq!(vec!["a", "b"], duplicate_keys(conf).unwrap());
+        assert!(duplicate_keys("a: 1\nb: {a: 2}\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(None, log_level(0, 0));
+        assert_eq!(Some(LevelFilter::Error), log_level(1, 0));
+        assert_eq!(Some(LevelFilter::Off), log_level(3, 0));
+        assert_eq!(Some(LevelFilter::Info), log_level(0, 1));
+        assert_eq!(Some(LevelFilter::Trace), log_level(0, 5));
+        assert_eq!(Some(LevelFilter::Warn), log_level(1, 1));
+    }
+
+    #[test]
+    fn test_record_sep() {
+        assert_eq!(Ok(RecordSep(b'\n')), "\\n".parse());
+        assert_eq!(Ok(RecordSep(0)), "\\0".parse());
+        assert_eq!(Ok(RecordSep(0x1e)), "\\x1e".parse());
+        assert_eq!(Ok(RecordSep(b',')), ",".parse());
+        assert!("\\x1".parse::<RecordSep>().is_err());
+        assert!("ab".parse::<RecordSep>().is_err());
+    }
+
+    #[test]
+    fn test_split_fields() {
+        let fields = split_fields("path, type,,data");
+        assert_eq!(3, fields.len());
+        assert!(fields.contains("type"));
+    }
+
+    #[test]
+    fn test_gen_job_id() {
+        let id = gen_job_id();
+        assert_eq!(36, id.len());
+        assert_eq!(Some('4'), id.chars().nth(14));
+        assert_ne!(id, gen_job_id());
+    }
+}
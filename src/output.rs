@@ -1,29 +1,167 @@
+use std::collections::HashSet;
 use std::fmt::{self, Display, Formatter};
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Write};
 use std::path::PathBuf;
 use std::process::{ChildStderr, ChildStdout};
-use std::thread::{self, ThreadId};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use encoding_rs::{Encoding, UTF_8};
 use log::{error, info};
+use serde::de::IgnoredAny;
 use serde_json::{Map, Value};
 
+use crate::input::{HashingReader, PluginStats};
+use crate::thread;
+
 pub static BUFSIZE: usize = 1024 * 1024;
 
-static NEWLINE: u8 = b"\n"[0];
+pub static NEWLINE: u8 = b"\n"[0];
 
 #[derive(Copy, Clone, Debug)]
-pub struct TaskId(ThreadId, u64);
+pub struct TaskId(u64, u64);
 
 impl TaskId {
     pub fn new(id: u64) -> TaskId {
-        TaskId(thread::current().id(), id)
+        TaskId(thread::current_id(), id)
+    }
+
+    // Sequential across the run, unlike the thread id
+    pub fn id(&self) -> u64 {
+        self.1
+    }
+
+    pub fn thread_id(&self) -> u64 {
+        self.0
     }
 }
 
 impl Display for TaskId {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Task({}.{})", self.0.as_u64(), self.1)
+        write!(f, "Task({}.{})", self.0, self.1)
+    }
+}
+
+// Run-wide state shared by all output threads
+#[derive(Debug)]
+pub struct OutputContext {
+    pub job_id: String,
+    // Written after each record. Records never contain a raw newline or NUL, embedded
+    // ones in plugin output are escaped by the JSON encoding.
+    pub record_sep: u8,
+    // Capacity of the buffers for reading plugin output
+    pub buffer_size: usize,
+    // Also write the thread that created a task next to its id
+    pub thread_ids: bool,
+    // Only these fields are written when set, the omitted ones never are
+    pub fields: Option<HashSet<String>>,
+    pub omit_fields: HashSet<String>,
+    // Order in which records were written, across all output threads
+    seq: AtomicU64,
+}
+
+impl OutputContext {
+    pub fn new<S: Into<String>>(job_id: S) -> OutputContext {
+        OutputContext {
+            job_id: job_id.into(),
+            record_sep: NEWLINE,
+            buffer_size: BUFSIZE,
+            thread_ids: false,
+            fields: None,
+            omit_fields: HashSet::new(),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, output: &Output) -> Map<String, Value> {
+        let mut map = Map::new();
+        map.insert("job_id".into(), self.job_id.clone().into());
+        map.insert("plugin".into(), output.plugin_name.clone().into());
+        map.insert("path".into(), output.item_path.to_string_lossy().into());
+        map.insert("origin".into(), output.origin.to_string_lossy().into());
+        map.insert("type".into(), output.item_type.clone().into());
+        if let Some(rule) = &output.detector_rule {
+            map.insert("detector_rule".into(), rule.clone().into());
+        }
+        if !output.tags.is_empty() {
+            map.insert("tags".into(), output.tags.clone().into());
+        }
+        if let Some(binary) = output.binary {
+            map.insert("binary".into(), binary.into());
+        }
+        // Seconds since the epoch
+        if let Some(mtime) = output.mtime.and_then(|x| x.duration_since(UNIX_EPOCH).ok()) {
+            map.insert("mtime".into(), mtime.as_secs_f64().into());
+        }
+        map
+    }
+
+    fn insert_task_id(&self, line: &mut Map<String, Value>, task_id: TaskId) {
+        line.insert("task_id".into(), task_id.id().into());
+        if self.thread_ids {
+            line.insert("thread_id".into(), task_id.thread_id().into());
+        }
+    }
+
+    fn keeps(&self, field: &str) -> bool {
+        self.fields.as_ref().is_none_or(|x| x.contains(field)) && !self.omit_fields.contains(field)
+    }
+
+    fn prune(&self, line: &mut Map<String, Value>) {
+        if self.fields.is_some() || !self.omit_fields.is_empty() {
+            let dropped = line
+                .keys()
+                .filter(|x| !self.keeps(x))
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in dropped {
+                line.remove(&key);
+            }
+        }
+    }
+
+    fn write_record<U: Write>(&self, line: &mut Map<String, Value>, mut exit: U) -> io::Result<()> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        line.insert("seq".into(), seq.into());
+        self.prune(line);
+        let mut out_buf = serde_json::to_vec(line)?;
+        out_buf.push(self.record_sep);
+        exit.write_all(&out_buf)
+    }
+
+    // Data has to be valid JSON, it's written as the first key of the record
+    fn write_raw_record<U: Write>(
+        &self,
+        line: &mut Map<String, Value>,
+        data: &str,
+        mut exit: U,
+    ) -> io::Result<()> {
+        if !self.keeps("data") {
+            return self.write_record(line, exit);
+        }
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        line.insert("seq".into(), seq.into());
+        self.prune(line);
+        let rest = serde_json::to_vec(line)?;
+        let mut out_buf = Vec::with_capacity(data.len() + rest.len() + 10);
+        out_buf.extend_from_slice(b"{\"data\":");
+        out_buf.extend_from_slice(data.as_bytes());
+        if line.is_empty() {
+            out_buf.push(b'}');
+        } else {
+            out_buf.push(b',');
+            out_buf.extend_from_slice(&rest[1..]);
+        }
+        out_buf.push(self.record_sep);
+        exit.write_all(&out_buf)
+    }
+}
+
+impl Default for OutputContext {
+    fn default() -> OutputContext {
+        OutputContext::new("")
     }
 }
 
@@ -31,8 +169,24 @@ impl Display for TaskId {
 pub struct Output {
     pub task_id: TaskId,
     pub item_path: PathBuf,
+    pub origin: PathBuf,
     pub item_type: String,
     pub plugin_name: String,
+    // Signature that matched the type, for outputs of a detected input
+    pub detector_rule: Option<String>,
+    // Tags of that signature, from the config
+    pub tags: Vec<String>,
+    // Whether the input looks binary, for outputs of an input
+    pub binary: Option<bool>,
+    pub mtime: Option<SystemTime>,
+    // Of the lines written by the plugin
+    pub encoding: &'static Encoding,
+    // Copy lines that are valid JSON without parsing them into a value
+    pub raw_json: bool,
+    // A missing output file isn't an error
+    pub optional: bool,
+    // Counts the bytes of data outputs for the plugin
+    pub plugin_stats: Option<Arc<PluginStats>>,
     pub data: OutputData,
 }
 
@@ -40,6 +194,7 @@ impl Output {
     pub fn new<P: Into<PathBuf>, S: Into<String>>(
         task_id: TaskId,
         item_path: P,
+        origin: PathBuf,
         item_type: S,
         plugin_name: S,
         data: OutputData,
@@ -47,22 +202,47 @@ impl Output {
         Output {
             task_id,
             item_path: item_path.into(),
+            origin,
             item_type: item_type.into(),
             plugin_name: plugin_name.into(),
+            detector_rule: None,
+            tags: Vec::new(),
+            binary: None,
+            mtime: None,
+            encoding: UTF_8,
+            raw_json: false,
+            optional: false,
+            plugin_stats: None,
             data,
         }
     }
 
-    pub fn handle<T: Write>(self, exit: &mut T) -> io::Result<()> {
+    pub fn handle<T: Write>(self, context: &OutputContext, exit: &mut T) -> io::Result<()> {
+        let line = context.record(&self);
         match self.data {
-            OutputData::File(path) => match File::open(&path) {
-                Ok(file) => copy_output(
-                    self.plugin_name,
-                    self.item_path,
-                    self.item_type,
-                    &mut BufReader::with_capacity(BUFSIZE, file),
-                    exit,
-                ),
+            OutputData::File(path, count) => match File::open(&path) {
+                Ok(file) => {
+                    let mut file = HashingReader::new(file);
+                    let (lines, bytes) = copy_output(
+                        context,
+                        line.clone(),
+                        &mut BufReader::with_capacity(context.buffer_size, &mut file),
+                        self.encoding,
+                        self.raw_json,
+                        &mut *exit,
+                    )?;
+                    count_bytes(&self.plugin_stats, bytes);
+                    // Identical artifacts of different inputs can be found by the digest
+                    let mut artifact = line.clone();
+                    artifact.insert("data_sha256".into(), file.digest().into());
+                    artifact.insert("lines".into(), lines.into());
+                    context.write_record(&mut artifact, &mut *exit)?;
+                    count_lines(context, count, lines, line, exit)
+                }
+                Err(_) if self.optional && !path.exists() => {
+                    info!("{}: No output file {:?}", self.task_id, path);
+                    count_lines(context, count, 0, line, exit)
+                }
                 Err(err) => {
                     if !path.exists() {
                         error!(
@@ -78,68 +258,479 @@ impl Output {
                     Err(err)
                 }
             },
-            OutputData::Stdout(out) => copy_output(
-                self.plugin_name,
-                self.item_path,
-                self.item_type,
-                &mut BufReader::with_capacity(BUFSIZE, out),
-                exit,
-            ),
-            OutputData::LogStdout(out) => log_output(
-                &mut BufReader::with_capacity(BUFSIZE, out),
+            OutputData::Stdout(out, count) => {
+                let reader = &mut BufReader::with_capacity(context.buffer_size, out);
+                let (lines, bytes) = copy_output(
+                    context,
+                    line.clone(),
+                    reader,
+                    self.encoding,
+                    self.raw_json,
+                    &mut *exit,
+                )?;
+                count_bytes(&self.plugin_stats, bytes);
+                count_lines(context, count, lines, line, exit)
+            }
+            OutputData::LogStdout(out, emit) => log_output(
+                context,
+                &mut BufReader::with_capacity(context.buffer_size, out),
                 &self.plugin_name,
+                self.encoding,
+                emit.then_some((line, "stdout")),
+                exit,
             ),
-            OutputData::LogStderr(err) => log_output(
-                &mut BufReader::with_capacity(BUFSIZE, err),
+            OutputData::LogStderr(err, emit) => log_output(
+                context,
+                &mut BufReader::with_capacity(context.buffer_size, err),
                 &self.plugin_name,
+                self.encoding,
+                emit.then_some((line, "stderr")),
+                exit,
             ),
+            OutputData::Error(msg) => {
+                let mut line = line;
+                line.insert("level".into(), "error".into());
+                context.insert_task_id(&mut line, self.task_id);
+                line.insert("error".into(), msg.into());
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Duplicate(digest) => {
+                let mut line = line;
+                line.insert("skipped".into(), true.into());
+                line.insert("reason".into(), SkipReason::Duplicate.as_str().into());
+                line.insert("duplicate".into(), true.into());
+                line.insert("sha256".into(), digest.into());
+                context.write_record(&mut line, exit)
+            }
+            OutputData::BombDetected(total) => {
+                let mut line = line;
+                line.insert("bomb_detected".into(), true.into());
+                line.insert("unpacked_bytes".into(), total.into());
+                context.write_record(&mut line, exit)
+            }
+            OutputData::UnexpectedType => {
+                let mut line = line;
+                line.insert("unexpected_type".into(), true.into());
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Processed => write_processed(context, line, exit),
+            OutputData::Skipped(reason) => {
+                let mut line = line;
+                line.insert("skipped".into(), true.into());
+                line.insert("reason".into(), reason.as_str().into());
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Symlink(target) => {
+                let mut line = line;
+                line.insert("symlink".into(), true.into());
+                line.insert("target".into(), target.to_string_lossy().into());
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Summary(summary) => {
+                let mut line = line;
+                line.insert("summary".into(), summary);
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Stage => {
+                let mut line = line;
+                line.insert("stage".into(), true.into());
+                context.insert_task_id(&mut line, self.task_id);
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Container(size, digest, hashed) => {
+                let mut line = line;
+                line.insert("container".into(), true.into());
+                line.insert("size".into(), size.into());
+                line.insert("sha256".into(), digest.into());
+                // A partial hash only covers the first sha256_bytes of the container
+                line.insert("sha256_partial".into(), (hashed < size).into());
+                line.insert("sha256_bytes".into(), hashed.into());
+                context.insert_task_id(&mut line, self.task_id);
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Children(count) => {
+                let mut line = line;
+                line.insert("children".into(), count.into());
+                context.insert_task_id(&mut line, self.task_id);
+                context.write_record(&mut line, exit)
+            }
+            OutputData::Throttled(waited_ms, dropped) => {
+                let mut line = line;
+                line.insert("throttled".into(), true.into());
+                line.insert("waited_ms".into(), waited_ms.into());
+                line.insert("dropped".into(), dropped.into());
+                context.insert_task_id(&mut line, self.task_id);
+                context.write_record(&mut line, exit)
+            }
         }
     }
 }
 
+// Lines of data written for a task, shared by all of its data outputs
+pub type LineCount = Arc<AtomicU64>;
+
 #[derive(Debug)]
 pub enum OutputData {
-    File(PathBuf),
-    Stdout(ChildStdout),
-    LogStdout(ChildStdout),
-    LogStderr(ChildStderr),
+    File(PathBuf, LineCount),
+    Stdout(ChildStdout, LineCount),
+    // The flag enables emitting the log lines as records as well
+    LogStdout(ChildStdout, bool),
+    LogStderr(ChildStderr, bool),
+    Error(String),
+    Duplicate(String),
+    // Total bytes unpacked from the origin
+    BombDetected(u64),
+    // Unpacked by the plugin of the record, which doesn't list the type in produces
+    UnexpectedType,
+    // The plugin finished without producing any data
+    Processed,
+    // The input was not processed, with the reason why
+    Skipped(SkipReason),
+    // An input that was not followed, with the path it points to
+    Symlink(PathBuf),
+    // Totals for the whole run, written last
+    Summary(Value),
+    // An unpacker ran and its outputs are scheduled as inputs
+    Stage,
+    // Size, sha256 and bytes hashed of the input of an unpacker, written after its outputs
+    // are scheduled
+    Container(u64, String, u64),
+    // Number of inputs scheduled from the output of an unpacker
+    Children(u64),
+    // Milliseconds waited for the spawn limiter before the task timed out and whether it
+    // was dropped instead of waiting on
+    Throttled(u64, bool),
+}
+
+// Why an input was not processed, all skipped records have a reason
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkipReason {
+    // No type was detected
+    UnknownType,
+    // The detected type has no enabled plugin
+    NoPlugin,
+    TooLarge,
+    Duplicate,
+    // Rejected by the filter of the plugin
+    Filtered,
+    // Of the same type as the unpacker it came from
+    SelfNested,
+    // Has no data, with the record policy for empty inputs
+    Empty,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::UnknownType => "unknown_type",
+            SkipReason::NoPlugin => "no_plugin",
+            SkipReason::TooLarge => "too_large",
+            SkipReason::Duplicate => "duplicate",
+            SkipReason::Filtered => "filtered",
+            SkipReason::SelfNested => "self_nested",
+            SkipReason::Empty => "empty",
+        }
+    }
 }
 
-fn log_output<T: BufRead>(output: &mut T, plugin_name: &str) -> io::Result<()> {
+fn log_output<T: BufRead, U: Write>(
+    context: &OutputContext,
+    output: &mut T,
+    plugin_name: &str,
+    encoding: &'static Encoding,
+    emit: Option<(Map<String, Value>, &str)>,
+    mut exit: U,
+) -> io::Result<()> {
     let mut buf = String::new();
-    while output.read_line(&mut buf)? > 0 {
+    let mut line = emit.map(|(mut map, level)| {
+        map.insert("level".into(), level.into());
+        map
+    });
+    loop {
+        let (len, lossy) = read_line(output, encoding, &mut buf)?;
+        if len == 0 {
+            break;
+        }
         info!("PLUGIN {}: {}", plugin_name, buf.trim());
+        if let Some(map) = &mut line {
+            map.insert("data".into(), buf.trim_end().into());
+            mark_lossy(map, lossy);
+            context.write_record(map, &mut exit)?;
+        }
         buf.clear();
     }
     Ok(())
 }
 
+// Appends a line decoded to UTF-8, invalid sequences are replaced. Lines are split on
+// the newline byte, so the encoding has to be ASCII compatible. Returns the bytes read
+// and whether any were replaced.
+fn read_line<T: BufRead>(
+    output: &mut T,
+    encoding: &'static Encoding,
+    buf: &mut String,
+) -> io::Result<(usize, bool)> {
+    let mut raw = Vec::new();
+    let len = output.read_until(NEWLINE, &mut raw)?;
+    let (decoded, lossy) = encoding.decode_without_bom_handling(&raw);
+    buf.push_str(&decoded);
+    Ok((len, lossy))
+}
+
+// Only the records of lines with replaced sequences have the field
+fn mark_lossy(line: &mut Map<String, Value>, lossy: bool) {
+    if lossy {
+        line.insert("lossy".into(), true.into());
+    } else {
+        line.remove("lossy");
+    }
+}
+
+// Returns the number of lines and bytes copied
 fn copy_output<T: BufRead, U: Write>(
-    plugin_name: String,
-    item_path: PathBuf,
-    item_type: String,
+    context: &OutputContext,
+    mut line: Map<String, Value>,
     output: &mut T,
+    encoding: &'static Encoding,
+    raw_json: bool,
     mut exit: U,
-) -> io::Result<()> {
+) -> io::Result<(u64, u64)> {
     let mut in_buf = String::new();
-    let mut out_buf = Vec::new();
-    let mut map = Map::new();
-    map.insert("plugin".into(), plugin_name.into());
-    map.insert("path".into(), item_path.to_str().unwrap().into());
-    map.insert("type".into(), item_type.into());
-    let mut line = Value::Object(map);
-    while output.read_line(&mut in_buf)? > 0 {
+    let mut lines = 0;
+    let mut bytes = 0;
+    loop {
+        let (len, lossy) = read_line(output, encoding, &mut in_buf)?;
+        if len == 0 {
+            break;
+        }
+        bytes += len as u64;
+        mark_lossy(&mut line, lossy);
 	let s = in_buf.trim_end();
+        // Only checked, which doesn't build the value in memory
+        if raw_json && serde_json::from_str::<IgnoredAny>(s).is_ok() {
+            line.remove("data");
+            context.write_raw_record(&mut line, s.trim_start(), &mut exit)?;
+            in_buf.clear();
+            lines += 1;
+            continue;
+        }
         let data = match serde_json::from_str(s) {
             Ok(x) => x,
             Err(_) => Value::String(s.to_string()),
         };
-        line.as_object_mut().unwrap().insert("data".into(), data);
-        serde_json::to_writer(&mut out_buf, &line)?;
-        out_buf.push(NEWLINE);
-        exit.write_all(&out_buf)?;
+        line.insert("data".into(), data);
+        context.write_record(&mut line, &mut exit)?;
         in_buf.clear();
-        out_buf.clear();
+        lines += 1;
+    }
+    Ok((lines, bytes))
+}
+
+fn count_bytes(plugin_stats: &Option<Arc<PluginStats>>, bytes: u64) {
+    if let Some(stats) = plugin_stats {
+        stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+// The last data output of a task to finish reports it if none of them had any data
+fn count_lines<U: Write>(
+    context: &OutputContext,
+    count: LineCount,
+    lines: u64,
+    line: Map<String, Value>,
+    exit: U,
+) -> io::Result<()> {
+    count.fetch_add(lines, Ordering::Relaxed);
+    match Arc::into_inner(count).map(AtomicU64::into_inner) {
+        Some(0) => write_processed(context, line, exit),
+        _ => Ok(()),
+    }
+}
+
+fn write_processed<U: Write>(
+    context: &OutputContext,
+    mut line: Map<String, Value>,
+    exit: U,
+) -> io::Result<()> {
+    line.insert("processed".into(), true.into());
+    line.insert("lines".into(), 0.into());
+    context.write_record(&mut line, exit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_seq() {
+        let context = OutputContext::default();
+        let mut buf = Vec::new();
+        for _ in 0..2 {
+            let data = OutputData::Error("foo".into());
+            let output = Output::new(TaskId::new(0), "", "".into(), "", "", data);
+            output.handle(&context, &mut buf).unwrap();
+        }
+        let seqs = serde_json::Deserializer::from_slice(&buf)
+            .into_iter::<Value>()
+            .map(|x| x.unwrap()["seq"].clone())
+            .collect::<Vec<Value>>();
+        assert_eq!(vec![Value::from(0), Value::from(1)], seqs);
+    }
+
+    #[test]
+    fn test_task_id() {
+        let mut context = OutputContext::default();
+        let record = |context: &OutputContext| {
+            let mut buf = Vec::new();
+            let output = Output::new(TaskId::new(7), "", "".into(), "", "", OutputData::Stage);
+            output.handle(context, &mut buf).unwrap();
+            serde_json::from_slice::<Value>(&buf).unwrap()
+        };
+        let result = record(&context);
+        assert_eq!(Some(&Value::from(7)), result.get("task_id"));
+        assert_eq!(None, result.get("thread_id"));
+        context.thread_ids = true;
+        let result = record(&context);
+        assert_eq!(
+            Some(&Value::from(thread::current_id())),
+            result.get("thread_id")
+        );
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut context = OutputContext::new("job");
+        context.fields = Some(["path", "data"].iter().map(|x| x.to_string()).collect());
+        let mut line = Map::new();
+        line.insert("path".into(), "foo".into());
+        line.insert("type".into(), "bar".into());
+        let mut buf = Vec::new();
+        context
+            .write_raw_record(&mut line.clone(), "[1]", &mut buf)
+            .unwrap();
+        context.fields = None;
+        context.omit_fields.insert("data".into());
+        context.omit_fields.insert("seq".into());
+        context
+            .write_raw_record(&mut line, "[1]", &mut buf)
+            .unwrap();
+        assert_eq!(
+            "{\"data\":[1],\"path\":\"foo\"}\n{\"path\":\"foo\",\"type\":\"bar\"}\n",
+            String::from_utf8(buf).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_record_lossy_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = PathBuf::from(OsStr::from_bytes(b"/foo/b\xffr"));
+        let output = Output::new(
+            TaskId::new(0),
+            &path,
+            path.clone(),
+            "",
+            "",
+            OutputData::Stage,
+        );
+        let mut buf = Vec::new();
+        output.handle(&OutputContext::default(), &mut buf).unwrap();
+        let result = serde_json::from_slice::<Value>(&buf).unwrap();
+        assert_eq!(Some(&Value::from("/foo/b\u{fffd}r")), result.get("path"));
+        assert_eq!(Some(&Value::from("/foo/b\u{fffd}r")), result.get("origin"));
+    }
+
+    #[test]
+    fn test_mtime() {
+        let context = OutputContext::default();
+        let mut output = Output::new(TaskId::new(0), "", "".into(), "", "", OutputData::Stage);
+        output.mtime = Some(UNIX_EPOCH + Duration::from_millis(1500));
+        let mut buf = Vec::new();
+        output.handle(&context, &mut buf).unwrap();
+        let result = serde_json::from_slice::<Value>(&buf).unwrap();
+        assert_eq!(Some(&Value::from(1.5)), result.get("mtime"));
+    }
+
+    #[test]
+    fn test_copy_output_raw_json() {
+        let context = OutputContext::new("job");
+        let mut line = Map::new();
+        line.insert("type".into(), "foo".into());
+        let mut input = &b"{\"b\": [1, 2]}\nnot json\n {\"c\":1}\n"[..];
+        let input_len = input.len() as u64;
+        let mut buf = Vec::new();
+        let (lines, bytes) =
+            copy_output(&context, line, &mut input, UTF_8, true, &mut buf).unwrap();
+        assert_eq!(3, lines);
+        assert_eq!(input_len, bytes);
+        let records = String::from_utf8(buf).unwrap();
+        let records = records.lines().collect::<Vec<_>>();
+        assert_eq!(
+            "{\"data\":{\"b\": [1, 2]},\"seq\":0,\"type\":\"foo\"}",
+            records[0]
+        );
+        assert_eq!(
+            "{\"data\":\"not json\",\"seq\":1,\"type\":\"foo\"}",
+            records[1]
+        );
+        assert_eq!(
+            "{\"data\":{\"c\":1},\"seq\":2,\"type\":\"foo\"}",
+            records[2]
+        );
+    }
+
+    #[test]
+    fn test_file_digest() {
+        let path = crate::plugin::gen_path(&std::env::temp_dir());
+        std::fs::write(&path, b"foo\nbar\n").unwrap();
+        let data = OutputData::File(path.clone(), LineCount::default());
+        let output = Output::new(TaskId::new(0), "", "".into(), "", "", data);
+        let mut buf = Vec::new();
+        output.handle(&OutputContext::default(), &mut buf).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let records = serde_json::Deserializer::from_slice(&buf)
+            .into_iter::<Value>()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(3, records.len());
+        assert_eq!(
+            Some(&Value::from(
+                "d78931fcf2660108eec0d6674ecb4e02401b5256a6b5ee82527766ef6d198c67"
+            )),
+            records[2].get("data_sha256")
+        );
+        assert_eq!(Some(&Value::from(2)), records[2].get("lines"));
+    }
+
+    #[test]
+    fn test_read_line_encoding() {
+        let mut buf = String::new();
+        let mut reader = &b"caf\xe9\n\xff"[..];
+        let (_, lossy) = read_line(&mut reader, encoding_rs::WINDOWS_1252, &mut buf).unwrap();
+        assert_eq!("caf\u{e9}\n", buf);
+        assert!(!lossy);
+        buf.clear();
+        let mut reader = &b"caf\xe9\n"[..];
+        let (_, lossy) = read_line(&mut reader, UTF_8, &mut buf).unwrap();
+        assert_eq!("caf\u{fffd}\n", buf);
+        assert!(lossy);
+    }
+
+    #[test]
+    fn test_copy_output_lossy() {
+        let context = OutputContext::default();
+        let mut buf = Vec::new();
+        let mut reader = &b"caf\xe9\nfoo\n"[..];
+        copy_output(&context, Map::new(), &mut reader, UTF_8, false, &mut buf).unwrap();
+        let records = serde_json::Deserializer::from_slice(&buf)
+            .into_iter::<Value>()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(Some(&Value::from(true)), records[0].get("lossy"));
+        assert_eq!(None, records[1].get("lossy"));
     }
-    Ok(())
 }
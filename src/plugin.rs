@@ -1,34 +1,98 @@
 use std::collections::HashMap;
-use std::env;
-use std::io;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::fmt::Write;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Duration;
 
-use serde::Deserialize;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use log::{debug, warn};
+use serde::{Deserialize, Deserializer};
 
 pub type Config = HashMap<FileType, Settings>;
 
+// Keys that aren't settings of the run are file types
+#[derive(Debug, Deserialize)]
+pub struct ConfigFile {
+    // Capacity of the buffers for reading plugin output. An output thread holds one
+    // while copying an output and there are 2 output threads per CPU, so memory use
+    // is up to buffer_size × 2 × CPUs. 64 KiB to a few MiB is sensible (default: 1 MiB).
+    pub buffer_size: Option<usize>,
+    // Joins the item_path of an unpacked input to the path of the file within the
+    // container, e.g. "!" for archive.zip!member/file (default: a path separator)
+    pub container_delimiter: Option<String>,
+    // Only hash the first this many bytes of the input of an unpacker for its container
+    // record, a quick hash of huge inputs for finding likely duplicates. Records mark a
+    // partial hash with sha256_partial and sha256_bytes (default: the whole input).
+    pub hash_bytes: Option<u64>,
+    // Whether text or hex signatures are tried first (default: best, all of them compete)
+    pub detection_order: Option<DetectionOrder>,
+    #[serde(flatten)]
+    pub types: Config,
+}
+
 pub type FileType = String;
 
+static HEADER_ENV_SIZE: usize = 256;
+
+static DEFAULT_SHELL: &str = "/bin/sh";
+
+static DIR_RETRIES: u32 = 10;
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
-    pub header: Header,
+    // A type matches if any of its headers matches
+    #[serde(rename = "header", default, deserialize_with = "one_or_many")]
+    pub headers: Vec<Header>,
+    // Matched against the end of input files, streamed inputs only match headers
+    #[serde(rename = "footer", default, deserialize_with = "one_or_many")]
+    pub footers: Vec<Header>,
     pub plugin: Plugin,
+    pub priority: Option<i32>,
+    // Scan up to this many bytes of input files for the headers of the type, when no
+    // type was found in the head. The window doubles from the head size up to the cap.
+    pub deep_scan: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Header {
     pub regex: String,
     pub hex: Option<bool>,
+    pub offset: Option<usize>,
+    pub case_insensitive: Option<bool>,
+    // Written to the records of inputs matched by this header, to classify them beyond
+    // their type, e.g. packed or encrypted
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Header>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Header),
+        Many(Vec<Header>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(header) => vec![header],
+        OneOrMany::Many(headers) => headers,
+    })
 }
 
 impl Header {
     pub fn is_hex(&self) -> bool {
 	self.hex.is_some() && self.hex.unwrap()
     }
+
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive.unwrap_or(false)
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct Plugin {
     pub name: String,
     pub path: PathBuf,
@@ -36,12 +100,188 @@ pub struct Plugin {
     pub input: Option<InputType>,
     pub output: Option<OutputType>,
     pub unpacker: Option<bool>,
+    // Expands ${INPUT_DIR}/${OUTPUT_DIR}; takes precedence over the dir of OutputType::dir.
+    pub cwd: Option<String>,
+    pub retries: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    // Also emit the plugin's log output (stderr and non-data stdout) as records
+    pub emit_logs: Option<bool>,
+    // File types an unpacker is expected to produce, others are flagged
+    pub produces: Option<Vec<FileType>>,
+    // Pass the start of the input as $HEADER_HEX
+    pub header_env: Option<bool>,
+    // Disabled types are left out of detection, so their inputs are unknown
+    pub enabled: Option<bool>,
+    // Temp dir passed as $OUTPUT to stdout plugins, removed when the plugin exits
+    pub scratch_dir: Option<bool>,
+    // Label of the encoding of the plugin's output lines (default: utf-8)
+    pub output_encoding: Option<String>,
+    // Output lines that are valid JSON are copied into records as they are instead of
+    // being parsed into a value first, for plugins writing large JSON values
+    pub raw_json: Option<bool>,
+    // Run before the plugin, the input is dropped if it exits non-zero
+    pub filter: Option<Filter>,
+    // Whether an unpacker may produce inputs of its own type (default: true),
+    // otherwise they are dropped as an unpack loop
+    pub allow_self_nesting: Option<bool>,
+    // Run the plugin with no_new_privs set, so it can't gain privileges through
+    // setuid binaries (Linux only)
+    pub sandbox: Option<bool>,
+    // Caps on the CPU time and address space of the plugin process (Unix only)
+    pub cpu_limit_secs: Option<u64>,
+    pub memory_limit_bytes: Option<u64>,
+    // Transforms the stdout of the plugin before it's read as records
+    pub post: Option<PostCommand>,
+    // The plugin may not write its output file, which is then not an error
+    pub output_optional: Option<bool>,
+    // Further output files or dirs, each passed as $VAR in the args and env var VAR
+    pub outputs: Option<Vec<ExtraOutput>>,
+    // Sample input run through the plugin by --selftest (default: a line of text)
+    pub test_input: Option<PathBuf>,
+    // Size of the writes of the input to the stdin of the plugin (default: as io::copy)
+    pub stdin_chunk_size: Option<usize>,
+    // An unpacker also writes a record for the container itself, with its size and sha256
+    pub emit_container: Option<bool>,
+    // Run path as a script with `shell_path -c path name args...`, so the args are the
+    // positional parameters $1, $2... of the script. Inputs are untrusted and so are the
+    // names of unpacked files, refer to paths as "$INPUT", "$OUTPUT" or "$1" in double
+    // quotes and never splice them into the script itself.
+    pub shell: Option<bool>,
+    // Shell that runs the script (default: /bin/sh)
+    pub shell_path: Option<PathBuf>,
+}
+
+// Gets the input file as $INPUT/INPUT, so the plugin needs file input
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Filter {
+    pub path: PathBuf,
+    pub args: Option<Vec<String>>,
+}
+
+// Gets the stdout of the plugin on its stdin, so the plugin needs stdout output
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PostCommand {
+    pub path: PathBuf,
+    pub args: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExtraOutput {
+    #[serde(rename = "type")]
+    pub output_type: OutputType,
+    pub var: String,
 }
 
 impl Plugin {
-    pub fn prep(&self, file_path: Option<&PathBuf>) -> io::Result<PreppedPlugin> {
-        let mut cmd = Command::new(&self.path);
-        let mut args = self.args.clone().unwrap_or(Vec::new());
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn allows_self_nesting(&self) -> bool {
+        self.allow_self_nesting.unwrap_or(true)
+    }
+
+    // Bare command names are left alone so they are still looked up in PATH
+    pub fn resolve_path(&mut self, base_dir: &Path) {
+        // The path of a shell plugin is a script
+        if self.shell.unwrap_or(false) {
+            if let Some(path) = &mut self.shell_path {
+                resolve(path, base_dir);
+            }
+        } else {
+            resolve(&mut self.path, base_dir);
+        }
+        if let Some(filter) = &mut self.filter {
+            resolve(&mut filter.path, base_dir);
+        }
+        if let Some(post) = &mut self.post {
+            resolve(&mut post.path, base_dir);
+        }
+        // Not a command, so a bare file name is relative to the config as well
+        if let Some(path) = &mut self.test_input {
+            *path = base_dir.join(&path);
+        }
+    }
+
+    // Checks the settings that would otherwise only fail once an input is prepped
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(ErrorKind::InvalidInput, msg));
+        for extra in self.outputs.as_deref().unwrap_or_default() {
+            if !is_var_name(&extra.var) {
+                return invalid(format!(
+                    "Output var {:?} of plugin {} is not a valid variable name",
+                    extra.var, self.name
+                ));
+            }
+        }
+        self.output_encoding()?;
+        Ok(())
+    }
+
+    // Output lines are split on the newline byte, which UTF-16 doesn't keep whole
+    fn output_encoding(&self) -> io::Result<&'static Encoding> {
+        let label = match &self.output_encoding {
+            Some(label) => label,
+            None => return Ok(UTF_8),
+        };
+        let invalid = |msg: &str| {
+            let msg = format!("{} output encoding {} of plugin {}", msg, label, self.name);
+            io::Error::new(ErrorKind::InvalidInput, msg)
+        };
+        match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) if encoding == UTF_16LE || encoding == UTF_16BE => {
+                Err(invalid("Unsupported"))
+            }
+            Some(encoding) => Ok(encoding),
+            None => Err(invalid("Unknown")),
+        }
+    }
+
+    // Temp files and output dirs are created in working_dir, which is also the
+    // default cwd of the plugin
+    pub fn prep(
+        &self,
+        file_path: Option<&PathBuf>,
+        head: &[u8],
+        working_dir: &Path,
+    ) -> io::Result<PreppedPlugin> {
+        let output_encoding = self.output_encoding()?;
+        if self.stdin_chunk_size == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The stdin_chunk_size of plugin {} must be above 0",
+                    self.name
+                ),
+            ));
+        }
+        let mut cmd = if self.shell.unwrap_or(false) {
+            let shell = self
+                .shell_path
+                .as_deref()
+                .unwrap_or(Path::new(DEFAULT_SHELL));
+            let mut cmd = Command::new(shell);
+            // The name is $0 of the script, so the args start at $1
+            cmd.arg("-c").arg(&self.path).arg(&self.name);
+            cmd
+        } else {
+            Command::new(&self.path)
+        };
+        cmd.current_dir(working_dir);
+        if self.sandbox.unwrap_or(false) {
+            sandbox(&mut cmd)?;
+        }
+        if self.cpu_limit_secs.is_some() || self.memory_limit_bytes.is_some() {
+            set_limits(&mut cmd, self.cpu_limit_secs, self.memory_limit_bytes)?;
+        }
+        if self.header_env.unwrap_or(false) {
+            let mut hex = String::with_capacity(HEADER_ENV_SIZE * 2);
+            for byte in head.iter().take(HEADER_ENV_SIZE) {
+                write!(hex, "{:02X}", byte).unwrap();
+            }
+            cmd.env("HEADER_HEX", hex);
+        }
+        let mut args = self.args.clone().unwrap_or_default();
         let input_type = self.input.unwrap_or(InputType::file);
         let output_type = self.output.unwrap_or(OutputType::file);
         let input_path = match input_type {
@@ -49,41 +289,266 @@ impl Plugin {
                 cmd.stdin(Stdio::piped());
                 InputPath::Stdin
             }
-            InputType::file => {
-                cmd.stdin(Stdio::null());
-                let path = file_path.map(|x| x.clone()).unwrap_or(gen_path()?);
+            InputType::file | InputType::both => {
+                let path = file_path.cloned().unwrap_or_else(|| gen_path(working_dir));
                 cmd.env("INPUT", &path);
-                replace_arg(&mut args, "$INPUT", &path.to_str().unwrap());
-                InputPath::File(path)
+                replace_arg(&mut args, "$INPUT", path.to_str().unwrap());
+                if input_type == InputType::both {
+                    cmd.stdin(Stdio::piped());
+                    InputPath::Both(path)
+                } else {
+                    cmd.stdin(Stdio::null());
+                    InputPath::File(path)
+                }
+            }
+        };
+        let filter = match &self.filter {
+            Some(filter) => {
+                let path = input_path.file().ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("The filter of plugin {} needs file input", self.name),
+                    )
+                })?;
+                let mut filter_args = filter.args.clone().unwrap_or_default();
+                replace_arg(&mut filter_args, "$INPUT", path.to_str().unwrap());
+                let mut filter_cmd = Command::new(&filter.path);
+                filter_cmd
+                    .current_dir(working_dir)
+                    .env("INPUT", path)
+                    .args(&filter_args)
+                    .stdin(Stdio::null());
+                Some(filter_cmd)
+            }
+            None => None,
+        };
+        let extras = self.outputs.as_deref().unwrap_or_default();
+        if let Some(extra) = extras
+            .iter()
+            .find(|x| !matches!(x.output_type, OutputType::file | OutputType::dir))
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Output {} of plugin {} has to be a file or dir",
+                    extra.var, self.name
+                ),
+            ));
+        }
+        let post = match &self.post {
+            Some(_) if !matches!(output_type, OutputType::stdout | OutputType::both) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "The post command of plugin {} needs stdout output",
+                        self.name
+                    ),
+                ));
+            }
+            Some(post) => {
+                let mut post_cmd = Command::new(&post.path);
+                post_cmd
+                    .current_dir(working_dir)
+                    .args(post.args.as_deref().unwrap_or_default())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped());
+                Some(post_cmd)
             }
+            None => None,
         };
+        let mut scratch_dir = None;
         let output_path = match output_type {
-            OutputType::stdout => OutputPath::Stdout,
-            OutputType::dir => {
-                let path = gen_path()?;
+            OutputType::stdout => {
+                if self.scratch_dir.unwrap_or(false) {
+                    let path = create_unique_dir(working_dir)?;
+                    cmd.env("OUTPUT", &path);
+                    replace_arg(&mut args, "$OUTPUT", path.to_str().unwrap());
+                    scratch_dir = Some(path);
+                }
+                OutputPath::Stdout
+            }
+            OutputType::dir | OutputType::both => {
+                // Created here so the path passed to the plugin is known to be unused
+                let path = create_unique_dir(working_dir)?;
                 cmd.env("OUTPUT", &path);
                 replace_arg(&mut args, "$OUTPUT", path.to_str().unwrap());
                 cmd.current_dir(&path);
-                OutputPath::Dir(path)
+                if output_type == OutputType::both {
+                    OutputPath::Both(path)
+                } else {
+                    OutputPath::Dir(path)
+                }
             }
             OutputType::file => {
-                let path = gen_path()?;
+                let path = gen_path(working_dir);
                 cmd.env("OUTPUT", &path);
                 replace_arg(&mut args, "$OUTPUT", path.to_str().unwrap());
                 OutputPath::File(path)
             }
         };
-        cmd.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut extra_outputs = Vec::with_capacity(extras.len());
+        for extra in extras {
+            let path = if extra.output_type == OutputType::dir {
+                match create_unique_dir(working_dir) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        // The dirs created so far aren't passed on to be removed later
+                        let dirs = iter::once(&output_path).chain(&extra_outputs);
+                        let dirs = dirs.filter_map(OutputPath::dir).chain(&scratch_dir);
+                        for dir in dirs {
+                            if let Err(err) = fs::remove_dir(dir) {
+                                warn!("Failed to remove {:?}: {}", dir, err);
+                            }
+                        }
+                        return Err(err);
+                    }
+                }
+            } else {
+                gen_path(working_dir)
+            };
+            cmd.env(&extra.var, &path);
+            replace_arg(
+                &mut args,
+                &format!("${}", extra.var),
+                path.to_str().unwrap(),
+            );
+            extra_outputs.push(if extra.output_type == OutputType::dir {
+                OutputPath::Dir(path)
+            } else {
+                OutputPath::File(path)
+            });
+        }
+        if let Some(cwd) = &self.cwd {
+            let input_dir = input_path.file().and_then(|x| x.parent());
+            let output_dir = match &output_path {
+                OutputPath::Dir(path) | OutputPath::Both(path) => Some(path.as_path()),
+                OutputPath::File(path) => path.parent(),
+                OutputPath::Stdout => scratch_dir.as_deref(),
+            };
+            let mut dir = cwd.clone();
+            expand_var(&mut dir, "${INPUT_DIR}", input_dir);
+            expand_var(&mut dir, "${OUTPUT_DIR}", output_dir);
+            cmd.current_dir(dir);
+        }
+        cmd.args(&args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
         Ok(PreppedPlugin {
             plugin_name: self.name.clone(),
             command: cmd,
+            args,
             input_path,
             output_path,
+            extra_outputs,
+            scratch_dir,
+            filter,
+            post,
+            output_encoding,
+            raw_json: self.raw_json.unwrap_or(false),
+            output_optional: self.output_optional.unwrap_or(false),
+            stdin_chunk_size: self.stdin_chunk_size,
+            emit_container: self.emit_container.unwrap_or(false),
+            cpu_limit: self.cpu_limit_secs,
+            memory_limit: self.memory_limit_bytes,
             unpacker: self.unpacker.unwrap_or(false),
+            retries: self.retries.unwrap_or(0),
+            retry_delay: Duration::from_millis(self.retry_delay_ms.unwrap_or(100)),
+            emit_logs: self.emit_logs.unwrap_or(false),
         })
     }
 }
 
+#[cfg(target_os = "linux")]
+fn sandbox(cmd: &mut Command) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+    // Only calls prctl between fork and exec, which is async-signal-safe
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sandbox(_: &mut Command) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "The plugin sandbox is only supported on Linux",
+    ))
+}
+
+// The hard CPU limit is a second later, so the plugin gets SIGXCPU before SIGKILL
+#[cfg(unix)]
+fn set_limits(
+    cmd: &mut Command,
+    cpu_secs: Option<u64>,
+    memory_bytes: Option<u64>,
+) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+    let limits = cpu_secs
+        .map(|x| (libc::RLIMIT_CPU, x, x + 1))
+        .into_iter()
+        .chain(memory_bytes.map(|x| (libc::RLIMIT_AS, x, x)))
+        .collect::<Vec<_>>();
+    // Only calls setrlimit between fork and exec, which is async-signal-safe
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, soft, hard) in limits.iter() {
+                let limit = libc::rlimit {
+                    rlim_cur: *soft as libc::rlim_t,
+                    rlim_max: *hard as libc::rlim_t,
+                };
+                if libc::setrlimit(*resource, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_limits(_: &mut Command, _: Option<u64>, _: Option<u64>) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "Plugin resource limits are only supported on Unix",
+    ))
+}
+
+// Reaps the child, along with the CPU time it used. It's not to be waited on or killed
+// through the Child again.
+#[cfg(unix)]
+pub fn wait_child(child: &mut Child) -> io::Result<(ExitStatus, Duration)> {
+    use std::os::unix::process::ExitStatusExt;
+    let mut status = 0;
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    while unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    let time = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+    let cpu_time = time(usage.ru_utime) + time(usage.ru_stime);
+    Ok((ExitStatus::from_raw(status), cpu_time))
+}
+
+#[cfg(not(unix))]
+pub fn wait_child(child: &mut Child) -> io::Result<(ExitStatus, Duration)> {
+    child.wait().map(|x| (x, Duration::ZERO))
+}
+
+fn resolve(path: &mut PathBuf, base_dir: &Path) {
+    if path.is_relative() && path.components().count() > 1 {
+        *path = base_dir.join(&path);
+    }
+}
+
 fn replace_arg(args: &mut Vec<String>, var: &str, rep: &str) {
     let idxs = args
         .iter()
@@ -97,12 +562,34 @@ fn replace_arg(args: &mut Vec<String>, var: &str, rep: &str) {
     }
 }
 
-pub fn gen_path() -> io::Result<PathBuf> {
-    let mut path = env::current_dir()?;
+fn expand_var(s: &mut String, var: &str, rep: Option<&Path>) {
+    if let Some(path) = rep {
+        *s = s.replace(var, path.to_str().unwrap());
+    }
+}
+
+pub fn gen_path(dir: &Path) -> PathBuf {
     let r: u64 = rand::random();
-    let name = format!("{:016x}", r);
-    path.push(name);
-    Ok(path)
+    dir.join(format!("{:016x}", r))
+}
+
+pub fn create_unique_dir(dir: &Path) -> io::Result<PathBuf> {
+    create_dir_with(|| gen_path(dir))
+}
+
+// Retries with a fresh path when the generated one already exists
+fn create_dir_with<F: FnMut() -> PathBuf>(mut gen: F) -> io::Result<PathBuf> {
+    let mut attempt = 0;
+    loop {
+        let path = gen();
+        match fs::create_dir(&path) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists && attempt < DIR_RETRIES => {
+                warn!("Dir {:?} already exists, retrying with another path", path);
+                attempt += 1;
+            }
+            result => return result.map(|_| path),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
@@ -110,6 +597,18 @@ pub fn gen_path() -> io::Result<PathBuf> {
 pub enum InputType {
     file,
     stdin,
+    both,
+}
+
+// With text_first or hex_first the other kind of signatures is only tried if the first
+// found nothing, regardless of priority
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum DetectionOrder {
+    #[default]
+    best,
+    text_first,
+    hex_first,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
@@ -118,33 +617,147 @@ pub enum OutputType {
     file,
     dir,
     stdout,
+    both,
 }
 
 #[derive(Debug)]
 pub struct PreppedPlugin {
     pub plugin_name: String,
     pub command: Command,
+    pub args: Vec<String>,
     pub input_path: InputPath,
     pub output_path: OutputPath,
+    // Only files and dirs
+    pub extra_outputs: Vec<OutputPath>,
+    pub scratch_dir: Option<PathBuf>,
+    pub filter: Option<Command>,
+    pub post: Option<Command>,
+    pub output_encoding: &'static Encoding,
+    pub raw_json: bool,
+    pub output_optional: bool,
+    pub stdin_chunk_size: Option<usize>,
+    pub emit_container: bool,
+    pub cpu_limit: Option<u64>,
+    pub memory_limit: Option<u64>,
     pub unpacker: bool,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub emit_logs: bool,
+}
+
+impl PreppedPlugin {
+    // Removes the dirs created by prep when the plugin won't run
+    pub fn remove_dirs(&self) -> io::Result<()> {
+        for path in self.output_paths().filter_map(OutputPath::dir) {
+            fs::remove_dir(path)?;
+        }
+        if let Some(path) = &self.scratch_dir {
+            fs::remove_dir(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn output_paths(&self) -> impl Iterator<Item = &OutputPath> {
+        iter::once(&self.output_path).chain(self.extra_outputs.iter())
+    }
+
+    // Explains an exit caused by the resource limits of the plugin. A SIGKILL can come from
+    // anywhere, it's only put down to the CPU limit if the plugin used that much CPU time.
+    // Over the memory limit allocations fail, which most plugins report with an exit code.
+    #[cfg(unix)]
+    pub fn check_limits(&self, status: &ExitStatus, cpu_time: Duration) -> Option<String> {
+        use std::os::unix::process::ExitStatusExt;
+        let over_cpu = |secs| cpu_time >= Duration::from_secs(secs);
+        match (status.signal(), self.cpu_limit, self.memory_limit) {
+            (Some(libc::SIGXCPU), Some(secs), _) => {
+                Some(format!("Plugin exceeded its CPU time limit of {} s", secs))
+            }
+            (Some(libc::SIGKILL), Some(secs), _) if over_cpu(secs) => {
+                Some(format!("Plugin exceeded its CPU time limit of {} s", secs))
+            }
+            (Some(signal @ (libc::SIGKILL | libc::SIGABRT | libc::SIGSEGV)), _, Some(bytes)) => {
+                Some(format!(
+                    "Plugin was killed by signal {}, possibly over its memory limit of {} bytes",
+                    signal, bytes
+                ))
+            }
+            (None, _, Some(bytes)) if !status.success() => Some(format!(
+                "Plugin exited with {}, possibly over its memory limit of {} bytes",
+                status, bytes
+            )),
+            _ => None,
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn check_limits(&self, _: &ExitStatus, _: Duration) -> Option<String> {
+        None
+    }
+
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        self.spawn_with(Command::spawn)
+    }
+
+    // Retries the spawn of the command while it fails with a transient error
+    fn spawn_with<F>(&mut self, mut spawn: F) -> io::Result<Child>
+    where
+        F: FnMut(&mut Command) -> io::Result<Child>,
+    {
+        debug!("Spawning plugin {} args: {:?}", self.plugin_name, self.args);
+        let mut attempt = 0;
+        loop {
+            match spawn(&mut self.command) {
+                Err(err) if attempt < self.retries && is_transient(&err) => {
+                    let delay = self.retry_delay * 2u32.saturating_pow(attempt);
+                    warn!(
+                        "Failed to spawn plugin {} (attempt {}), retrying in {:?}: {}",
+                        self.plugin_name,
+                        attempt + 1,
+                        delay,
+                        err
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// [A-Za-z_][A-Za-z0-9_]*
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|x| x == '_' || x.is_ascii_alphabetic())
+        && chars.all(|x| x == '_' || x.is_ascii_alphanumeric())
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::OutOfMemory | ErrorKind::Interrupted
+    )
 }
 
 #[derive(Debug, PartialEq)]
 pub enum InputPath {
     File(PathBuf),
     Stdin,
+    Both(PathBuf),
 }
 
 impl InputPath {
     pub fn file(&self) -> Option<&PathBuf> {
         match self {
-            InputPath::File(path) => Some(path),
+            InputPath::File(path) | InputPath::Both(path) => Some(path),
             InputPath::Stdin => None,
         }
     }
 
     pub fn stdin(&self) -> bool {
-        self == &InputPath::Stdin
+        matches!(self, InputPath::Stdin | InputPath::Both(_))
     }
 }
 
@@ -153,18 +766,39 @@ pub enum OutputPath {
     Dir(PathBuf),
     File(PathBuf),
     Stdout,
+    Both(PathBuf),
 }
 
 impl OutputPath {
     pub fn dir(&self) -> Option<&PathBuf> {
         match self {
-            OutputPath::Dir(path) => Some(path),
+            OutputPath::Dir(path) | OutputPath::Both(path) => Some(path),
             _ => None,
         }
     }
 
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            OutputPath::Dir(path) | OutputPath::File(path) | OutputPath::Both(path) => Some(path),
+            OutputPath::Stdout => None,
+        }
+    }
+
     pub fn stdout(&self) -> bool {
-        self == &OutputPath::Stdout
+        matches!(self, OutputPath::Stdout | OutputPath::Both(_))
+    }
+
+    // Describes output of another kind than the output type in the config
+    pub fn check_kind(&self) -> Option<String> {
+        match self {
+            OutputPath::File(path) if path.is_dir() => {
+                Some("Plugin wrote a dir, but its output type is file".into())
+            }
+            OutputPath::Dir(path) | OutputPath::Both(path) if path.is_file() => {
+                Some("Plugin wrote a file, but its output type is dir".into())
+            }
+            _ => None,
+        }
     }
 }
 
@@ -172,6 +806,20 @@ impl OutputPath {
 mod tests {
     use super::*;
 
+    use std::env;
+
+    #[test]
+    fn test_create_dir_collision() {
+        let taken = create_unique_dir(&env::temp_dir()).unwrap();
+        let fresh = gen_path(&env::temp_dir());
+        let mut paths = vec![fresh.clone(), taken.clone()];
+        let path = create_dir_with(|| paths.pop().unwrap()).unwrap();
+        assert_eq!(fresh, path);
+        assert!(path.is_dir());
+        fs::remove_dir(taken).unwrap();
+        fs::remove_dir(path).unwrap();
+    }
+
     #[test]
     fn test_prep() {
         let plugin = Plugin {
@@ -181,23 +829,319 @@ mod tests {
             input: None,
             output: Some(OutputType::stdout),
             unpacker: None,
+            ..Default::default()
         };
-        let prepped = plugin.prep(None).unwrap();
+        let prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
         assert_eq!(
             Some(&prepped.input_path),
             prepped
-                .command
-                .get_args()
-                .nth(1)
-                .and_then(|x| x.to_str())
+                .args
+                .get(1)
                 .map(|x| InputPath::File(PathBuf::from(x)))
                 .as_ref()
         );
         assert!(prepped.output_path.stdout());
-        let prepped = plugin.prep(Some(&"/foo/bar".into())).unwrap();
+        let prepped = plugin
+            .prep(Some(&"/foo/bar".into()), &[], &env::temp_dir())
+            .unwrap();
+        assert_eq!(Some("/foo/bar"), prepped.args.get(1).map(|x| x.as_str()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_prep_header_env() {
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "echo $HEADER_HEX".into()]),
+            input: Some(InputType::stdin),
+            output: Some(OutputType::stdout),
+            header_env: Some(true),
+            ..Default::default()
+        };
+        let mut prepped = plugin.prep(None, &[0x1f; 300], &env::temp_dir()).unwrap();
+        let out = prepped.command.output().unwrap();
+        assert_eq!(
+            format!("{}\n", "1F".repeat(256)).as_bytes(),
+            out.stdout.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_validate_output_vars() {
+        let plugin = |var: &str| Plugin {
+            name: "foo".into(),
+            path: "foo".into(),
+            outputs: Some(vec![ExtraOutput {
+                output_type: OutputType::file,
+                var: var.into(),
+            }]),
+            ..Default::default()
+        };
+        assert!(plugin("REPORT").validate().is_ok());
+        assert!(plugin("_dir_2").validate().is_ok());
+        assert!(plugin("2DIR").validate().is_err());
+        assert!(plugin("A-B").validate().is_err());
+        assert!(plugin("A=B").validate().is_err());
+        assert!(plugin("").validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_output_encoding() {
+        let plugin = |label: &str| Plugin {
+            name: "foo".into(),
+            path: "foo".into(),
+            output_encoding: Some(label.into()),
+            ..Default::default()
+        };
+        assert!(plugin("latin1").validate().is_ok());
+        assert!(plugin("shift_jis").validate().is_ok());
+        assert!(plugin("nonexistent").validate().is_err());
+        assert!(plugin("utf-16le").validate().is_err());
+        assert!(plugin("utf-16").validate().is_err());
+        assert!(Plugin::default().validate().is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_spawn_retries() {
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/true".into(),
+            output: Some(OutputType::stdout),
+            retries: Some(2),
+            retry_delay_ms: Some(1),
+            ..Default::default()
+        };
+        let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+        let mut attempts = 0;
+        let mut child = prepped
+            .spawn_with(|cmd| {
+                attempts += 1;
+                if attempts < 3 {
+                    Err(io::Error::from_raw_os_error(libc::EAGAIN))
+                } else {
+                    cmd.spawn()
+                }
+            })
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(3, attempts);
+        let mut attempts = 0;
+        let err = prepped
+            .spawn_with(|_| {
+                attempts += 1;
+                Err(io::Error::from_raw_os_error(libc::EAGAIN))
+            })
+            .unwrap_err();
+        assert_eq!(ErrorKind::WouldBlock, err.kind());
+        assert_eq!(3, attempts);
+        // Not transient, so not retried
+        let mut attempts = 0;
+        prepped
+            .spawn_with(|_| {
+                attempts += 1;
+                Err(io::Error::from_raw_os_error(libc::ENOENT))
+            })
+            .unwrap_err();
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_prep_cwd() {
+        let mut plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "pwd".into()]),
+            input: None,
+            output: Some(OutputType::stdout),
+            unpacker: None,
+            cwd: Some("/".into()),
+            ..Default::default()
+        };
+        let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+        let out = prepped.command.output().unwrap();
+        assert_eq!(b"/\n", out.stdout.as_slice());
+        plugin.cwd = Some("${INPUT_DIR}".into());
+        let mut prepped = plugin
+            .prep(Some(&"/tmp/foo".into()), &[], &env::temp_dir())
+            .unwrap();
+        let out = prepped.command.output().unwrap();
+        assert_eq!(b"/tmp\n", out.stdout.as_slice());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_prep_cwd_windows() {
+        let mut plugin = Plugin {
+            name: "foo".into(),
+            path: "cmd".into(),
+            args: Some(vec!["/C".into(), "cd".into()]),
+            output: Some(OutputType::stdout),
+            cwd: Some("C:\\".into()),
+            ..Default::default()
+        };
+        let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+        let out = prepped.command.output().unwrap();
+        assert_eq!(b"C:\\\r\n", out.stdout.as_slice());
+        plugin.cwd = Some("${INPUT_DIR}".into());
+        let mut prepped = plugin
+            .prep(Some(&"C:\\Windows\\foo".into()), &[], &env::temp_dir())
+            .unwrap();
+        let out = prepped.command.output().unwrap();
+        assert_eq!(b"C:\\Windows\r\n", out.stdout.as_slice());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_prep_shell() {
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "printf '%s\\n' \"$1\" | tr a-z A-Z".into(),
+            args: Some(vec!["bar; echo $HOME".into()]),
+            output: Some(OutputType::stdout),
+            shell: Some(true),
+            ..Default::default()
+        };
+        let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+        let out = prepped.command.output().unwrap();
+        assert!(out.status.success());
+        assert_eq!(b"BAR; ECHO $HOME\n", out.stdout.as_slice());
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let mut plugin = Plugin {
+            name: "foo".into(),
+            path: "plugins/foo.sh".into(),
+            args: None,
+            input: None,
+            output: None,
+            unpacker: None,
+            ..Default::default()
+        };
+        plugin.resolve_path(Path::new("/etc/factory"));
+        assert_eq!(PathBuf::from("/etc/factory/plugins/foo.sh"), plugin.path);
+        plugin.resolve_path(Path::new("/other"));
+        assert_eq!(PathBuf::from("/etc/factory/plugins/foo.sh"), plugin.path);
+        plugin.path = "gunzip".into();
+        plugin.resolve_path(Path::new("/etc/factory"));
+        assert_eq!(PathBuf::from("gunzip"), plugin.path);
+        plugin.path = "gunzip | bin/foo".into();
+        plugin.shell = Some(true);
+        plugin.shell_path = Some("bin/sh".into());
+        plugin.resolve_path(Path::new("/etc/factory"));
+        assert_eq!(PathBuf::from("gunzip | bin/foo"), plugin.path);
         assert_eq!(
-            Some("/foo/bar"),
-            prepped.command.get_args().nth(1).and_then(|x| x.to_str())
+            Some(PathBuf::from("/etc/factory/bin/sh")),
+            plugin.shell_path
         );
     }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sandbox() {
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/grep".into(),
+            args: Some(vec!["NoNewPrivs".into(), "/proc/self/status".into()]),
+            output: Some(OutputType::stdout),
+            sandbox: Some(true),
+            ..Default::default()
+        };
+        let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+        let out = prepped.command.output().unwrap();
+        assert_eq!(b"NoNewPrivs:\t1\n", out.stdout.as_slice());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cpu_limit() {
+        let plugin = Plugin {
+            name: "foo".into(),
+            path: "/bin/sh".into(),
+            args: Some(vec!["-c".into(), "while :; do :; done".into()]),
+            output: Some(OutputType::stdout),
+            cpu_limit_secs: Some(1),
+            ..Default::default()
+        };
+        let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+        let (status, cpu_time) = wait_child(&mut prepped.command.spawn().unwrap()).unwrap();
+        assert_eq!(
+            Some("Plugin exceeded its CPU time limit of 1 s".into()),
+            prepped.check_limits(&status, cpu_time)
+        );
+        // Killed from outside, well under the limit
+        let plugin = Plugin {
+            args: Some(vec!["-c".into(), "exec sleep 10".into()]),
+            ..plugin
+        };
+        let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+        let mut child = prepped.command.spawn().unwrap();
+        child.kill().unwrap();
+        let (status, cpu_time) = wait_child(&mut child).unwrap();
+        assert_eq!(None, prepped.check_limits(&status, cpu_time));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_memory_limit() {
+        let check = |script: &str| {
+            let plugin = Plugin {
+                name: "foo".into(),
+                path: "/bin/sh".into(),
+                args: Some(vec!["-c".into(), script.into()]),
+                output: Some(OutputType::stdout),
+                memory_limit_bytes: Some(1 << 30),
+                ..Default::default()
+            };
+            let mut prepped = plugin.prep(None, &[], &env::temp_dir()).unwrap();
+            let (status, cpu_time) = wait_child(&mut prepped.command.spawn().unwrap()).unwrap();
+            prepped.check_limits(&status, cpu_time)
+        };
+        let exited = check("exit 3").unwrap();
+        assert!(
+            exited.starts_with("Plugin exited with exit status: 3"),
+            "{}",
+            exited
+        );
+        assert!(
+            exited.ends_with("memory limit of 1073741824 bytes"),
+            "{}",
+            exited
+        );
+        assert!(check("kill -KILL $$")
+            .unwrap()
+            .contains("killed by signal 9"));
+        assert_eq!(None, check("kill -PIPE $$"));
+        assert_eq!(None, check("exit 0"));
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config: ConfigFile = serde_yaml::from_str(
+            "buffer_size: 4096\ncontainer_delimiter: '!'\ndetection_order: hex_first\n\
+             hash_bytes: 1024\nfoo: {header: {regex: ^foo}, plugin: {name: foo, path: foo}}",
+        )
+        .unwrap();
+        assert_eq!(Some(4096), config.buffer_size);
+        assert_eq!(Some("!".into()), config.container_delimiter);
+        assert_eq!(Some(DetectionOrder::hex_first), config.detection_order);
+        assert_eq!(Some(1024), config.hash_bytes);
+        assert_eq!(vec!["foo"], config.types.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_settings_headers() {
+        let one: Settings =
+            serde_yaml::from_str("header: {regex: ^foo}\nplugin: {name: foo, path: foo}").unwrap();
+        assert_eq!(1, one.headers.len());
+        let many: Settings = serde_yaml::from_str(
+            "header: [{regex: ^foo}, {regex: 00 01, hex: true}]\nplugin: {name: foo, path: foo}",
+        )
+        .unwrap();
+        assert_eq!(2, many.headers.len());
+        assert!(many.headers[1].is_hex());
+    }
 }
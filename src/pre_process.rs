@@ -1,51 +1,387 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::fmt::Write;
-use std::io::{self, Chain, Cursor, Read};
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, Chain, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use crossbeam_channel::bounded;
 use log::{debug, info, warn};
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 
-use crate::output::TaskId;
-use crate::plugin::{Config, FileType, Plugin, PreppedPlugin};
+use crate::output::{Output, OutputData, SkipReason, TaskId};
+use crate::plugin::{Config, DetectionOrder, FileType, Header, Plugin, PreppedPlugin, Settings};
+
+pub type SniffedData<R> = Chain<Cursor<Vec<u8>>, R>;
+
+pub static SNIFF_SIZE: u64 = 4096;
+
+static HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+thread_local! {
+    // The hex string of the data matched by the hex signatures, reused across inputs
+    static DATA_HEX: RefCell<String> =
+        RefCell::new(String::with_capacity(SNIFF_SIZE as usize * 2));
+}
+
+// Type that empty inputs are processed as with EmptyInputs::Route
+pub static EMPTY_TYPE: &str = "empty";
+
+// What to do with inputs without any data, which no signature can match
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EmptyInputs {
+    // Detected like other inputs, so they are skipped as unknown
+    Detect,
+    // Skipped without a record
+    Skip,
+    // Skipped with a record
+    Record,
+    // Processed as the empty type of the config
+    Route,
+}
+
+impl FromStr for EmptyInputs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EmptyInputs, String> {
+        match s {
+            "detect" => Ok(EmptyInputs::Detect),
+            "skip" => Ok(EmptyInputs::Skip),
+            "record" => Ok(EmptyInputs::Record),
+            "route" => Ok(EmptyInputs::Route),
+            _ => Err(format!("Invalid empty input policy: {}", s)),
+        }
+    }
+}
 
 pub struct PreProcessedInput<T> {
     pub task_id: TaskId,
     pub item_path: PathBuf,
+    pub origin: PathBuf,
     pub item_type: String,
+    // Identifies the signature that matched the type, if it was detected
+    pub detector_rule: Option<String>,
+    // Tags of that signature
+    pub tags: Vec<String>,
+    // Judged from the start of the input, independent of its type
+    pub binary: bool,
+    // Modification time of an input file, not of extracted or streamed inputs
+    pub mtime: Option<SystemTime>,
     pub plugin: PreppedPlugin,
     pub data: T,
 }
 
+impl<T> PreProcessedInput<T> {
+    // Splits off the data, so it can be read on another thread
+    pub fn take_data(self) -> (T, PreProcessedInput<()>) {
+        let PreProcessedInput {
+            task_id,
+            item_path,
+            origin,
+            item_type,
+            detector_rule,
+            tags,
+            binary,
+            mtime,
+            plugin,
+            data,
+        } = self;
+        let ppi = PreProcessedInput {
+            task_id,
+            item_path,
+            origin,
+            item_type,
+            detector_rule,
+            tags,
+            binary,
+            mtime,
+            plugin,
+            data: (),
+        };
+        (data, ppi)
+    }
+
+    pub fn output(&self, data: OutputData) -> Output {
+        let mut output = Output::new(
+            self.task_id,
+            self.item_path.clone(),
+            self.origin.clone(),
+            self.item_type.clone(),
+            self.plugin.plugin_name.clone(),
+            data,
+        );
+        output.detector_rule = self.detector_rule.clone();
+        output.tags = self.tags.clone();
+        output.binary = Some(self.binary);
+        output.mtime = self.mtime;
+        output.encoding = self.plugin.output_encoding;
+        output.raw_json = self.plugin.raw_json;
+        output.optional = self.plugin.output_optional;
+        output
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Detection {
+    pub file_type: FileType,
+    pub rule: String,
+    pub tags: Vec<String>,
+}
+
+pub struct Signature {
+    pub regex: Regex,
+    // The regex as written in the config
+    pub rule: String,
+    pub offset: usize,
+    pub priority: i32,
+    pub tags: Vec<String>,
+}
+
+impl Signature {
+    fn new(settings: &Settings, header: &Header, regex: Regex) -> Signature {
+        Signature {
+            regex,
+            rule: header.regex.clone(),
+            offset: header.offset.unwrap_or(0),
+            priority: settings.priority.unwrap_or(0),
+            tags: header.tags.clone(),
+        }
+    }
+}
+
+pub trait Detector: Send + Sync {
+    // The tail is empty for inputs that can't be seeked
+    fn detect(&self, head: &[u8], tail: &[u8]) -> Option<Detection>;
+}
+
+// Priority, match length, type, rule and tags of the best match so far
+type Best<'a> = Option<(i32, usize, Reverse<&'a FileType>, &'a str, &'a [String])>;
+
+// The compiled rules for one end of the input
+pub struct Signatures {
+    pub compiled: Vec<(FileType, Signature)>,
+    pub compiled_hex: Vec<(FileType, Signature)>,
+}
+
+impl Signatures {
+    // Of the enabled types that keep returns true for
+    fn new<K, F>(config: &Config, keep: K, rules: F) -> Signatures
+    where
+        K: Fn(&FileType) -> bool,
+        F: Fn(&Settings) -> &Vec<Header>,
+    {
+        let rules = || {
+            config
+                .iter()
+                .filter(|(t, s)| s.plugin.is_enabled() && keep(t))
+                .flat_map(|(t, s)| rules(s).iter().map(move |h| (t, s, h)))
+        };
+        let compiled = rules()
+            .filter(|(_, _, h)| !h.is_hex())
+            .map(|(t, s, h)| {
+                let re = RegexBuilder::new(&h.regex)
+                    .case_insensitive(h.is_case_insensitive())
+                    .build()
+                    .unwrap();
+                (t.clone(), Signature::new(s, h, re))
+            })
+            .collect();
+        let compiled_hex = rules()
+            .filter(|(_, _, h)| h.is_hex())
+            .map(|(t, s, h)| {
+                let mut re = h.regex.replace(' ', "");
+                re.make_ascii_uppercase();
+                (t.clone(), Signature::new(s, h, Regex::new(&re).unwrap()))
+            })
+            .collect();
+        Signatures {
+            compiled,
+            compiled_hex,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.compiled.is_empty() && self.compiled_hex.is_empty()
+    }
+
+    fn find_text<'a>(&'a self, data: &[u8], best: &mut Best<'a>) {
+        if self.compiled.is_empty() {
+            return;
+        }
+        let data_str = String::from_utf8_lossy(data);
+        for (t, s) in self.compiled.iter() {
+            let len = match s.offset {
+                0 => s.regex.find(&data_str).map(|m| m.as_str().len()),
+                n => data.get(n..).and_then(|x| {
+                    s.regex
+                        .find(&String::from_utf8_lossy(x))
+                        .map(|m| m.as_str().len())
+                }),
+            };
+            if let Some(len) = len {
+                consider(best, t, s, len);
+            }
+        }
+    }
+
+    fn find_hex<'a>(&'a self, data: &[u8], best: &mut Best<'a>) {
+        if self.compiled_hex.is_empty() {
+            return;
+        }
+        DATA_HEX.with(|data_hex| {
+            let mut data_hex = data_hex.borrow_mut();
+            data_hex.clear();
+            for byte in data {
+                data_hex.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+                data_hex.push(HEX_DIGITS[(byte & 0xf) as usize] as char);
+            }
+            for (t, s) in self.compiled_hex.iter() {
+                let len = data_hex
+                    .get(s.offset * 2..)
+                    .and_then(|x| s.regex.find(x))
+                    .map(|m| m.as_str().len() / 2);
+                if let Some(len) = len {
+                    consider(best, t, s, len);
+                }
+            }
+        })
+    }
+}
+
+fn consider<'a>(best: &mut Best<'a>, t: &'a FileType, s: &'a Signature, len: usize) {
+    let candidate = Some((s.priority, len, Reverse(t), s.rule.as_str(), &s.tags[..]));
+    if candidate > *best {
+        *best = candidate;
+    }
+}
+
+pub struct RegexDetector {
+    pub headers: Signatures,
+    // Matched against the tail, only seekable inputs have one
+    pub footers: Signatures,
+    pub order: DetectionOrder,
+}
+
+impl RegexDetector {
+    pub fn new(config: &Config) -> RegexDetector {
+        RegexDetector::with_types(config, |_| true)
+    }
+
+    fn with_types<K: Fn(&FileType) -> bool>(config: &Config, keep: K) -> RegexDetector {
+        RegexDetector {
+            headers: Signatures::new(config, &keep, |s| &s.headers),
+            footers: Signatures::new(config, &keep, |s| &s.footers),
+            order: DetectionOrder::default(),
+        }
+    }
+
+    fn find_text<'a>(&'a self, head: &[u8], tail: &[u8], best: &mut Best<'a>) {
+        self.headers.find_text(head, best);
+        if !tail.is_empty() {
+            self.footers.find_text(tail, best);
+        }
+    }
+
+    fn find_hex<'a>(&'a self, head: &[u8], tail: &[u8], best: &mut Best<'a>) {
+        self.headers.find_hex(head, best);
+        if !tail.is_empty() {
+            self.footers.find_hex(tail, best);
+        }
+    }
+}
+
+impl Detector for RegexDetector {
+    // Picks the match with the highest priority, then the longest match in bytes.
+    // Remaining ties are broken on the type name so the result doesn't depend on
+    // the HashMap iteration order. Header and footer matches compete the same way.
+    // With text_first or hex_first only the matches of the first kind compete, if any.
+    fn detect(&self, head: &[u8], tail: &[u8]) -> Option<Detection> {
+        let mut best = None;
+        match self.order {
+            DetectionOrder::best => {
+                self.find_text(head, tail, &mut best);
+                self.find_hex(head, tail, &mut best);
+            }
+            DetectionOrder::text_first => {
+                self.find_text(head, tail, &mut best);
+                if best.is_none() {
+                    self.find_hex(head, tail, &mut best);
+                }
+            }
+            DetectionOrder::hex_first => {
+                self.find_hex(head, tail, &mut best);
+                if best.is_none() {
+                    self.find_text(head, tail, &mut best);
+                }
+            }
+        }
+        best.map(|(_, _, t, rule, tags)| Detection {
+            file_type: t.0.clone(),
+            rule: rule.into(),
+            tags: tags.to_vec(),
+        })
+    }
+}
+
+// Tries each detector in order, the first one to find a type wins
+pub struct ChainDetector(pub Vec<Box<dyn Detector>>);
+
+impl Detector for ChainDetector {
+    fn detect(&self, head: &[u8], tail: &[u8]) -> Option<Detection> {
+        self.0.iter().find_map(|x| x.detect(head, tail))
+    }
+}
+
 pub struct PreProcessor {
     pub plugins: HashMap<FileType, Plugin>,
-    pub compiled: HashMap<FileType, Regex>,
-    pub compiled_hex: HashMap<FileType, Regex>,
+    pub detector: Box<dyn Detector>,
+    pub working_dir: PathBuf,
+    // Type of the data read from stdin, skips detection
+    pub stdin_type: Option<FileType>,
+    // For reading the head of the stdout of an unpacker
+    pub sniff_timeout: Option<Duration>,
+    pub empty_inputs: EmptyInputs,
+    // Only read the tail of files when there are footers to match
+    read_tail: bool,
+    // The cap and headers of each type with a deep scan, by priority then type
+    deep_scan: Vec<(u64, RegexDetector)>,
 }
 
 impl PreProcessor {
-    pub fn new(config: &Config) -> PreProcessor {
-        let compiled = config
-            .iter()
-            .filter(|(_, s)| !s.header.is_hex())
-            .map(|(t, s)| (t.clone(), Regex::new(&s.header.regex).unwrap()))
-            .collect();
-        let compiled_hex = config
+    pub fn new(config: &Config, order: DetectionOrder, working_dir: PathBuf) -> PreProcessor {
+        let mut regex_detector = RegexDetector::new(config);
+        regex_detector.order = order;
+        let mut deep_scan = config
             .iter()
-            .filter(|(_, s)| s.header.is_hex())
-            .map(|(t, s)| {
-                let mut re = s.header.regex.replace(" ", "");
-                re.make_ascii_uppercase();
-                (t.clone(), Regex::new(&re).unwrap())
+            .filter(|(_, s)| s.plugin.is_enabled() && !s.headers.is_empty())
+            .filter_map(|(t, s)| Some((s.deep_scan.filter(|x| *x > SNIFF_SIZE)?, t, s)))
+            .collect::<Vec<_>>();
+        deep_scan.sort_by_key(|(_, t, s)| (Reverse(s.priority.unwrap_or(0)), *t));
+        let deep_scan = deep_scan
+            .into_iter()
+            .map(|(cap, t, _)| {
+                let mut detector = RegexDetector::with_types(config, |x| x == t);
+                detector.order = order;
+                (cap, detector)
             })
             .collect();
         PreProcessor {
+            deep_scan,
+            working_dir,
+            stdin_type: None,
+            sniff_timeout: None,
+            empty_inputs: EmptyInputs::Detect,
+            read_tail: !regex_detector.footers.is_empty(),
             plugins: config
                 .iter()
+                .filter(|(_, s)| s.plugin.is_enabled())
                 .map(|(t, s)| (t.clone(), s.plugin.clone()))
                 .collect(),
-            compiled,
-            compiled_hex,
+            // Further detection engines go after the regex signatures
+            detector: Box::new(ChainDetector(vec![Box::new(regex_detector)])),
         }
     }
 
@@ -53,24 +389,67 @@ impl PreProcessor {
         &self,
         task_id: TaskId,
         item_path: PathBuf,
+        origin: PathBuf,
         file_path: Option<&PathBuf>,
+        type_hint: Option<&FileType>,
         mut data: R,
-    ) -> io::Result<Option<PreProcessedInput<Chain<Cursor<Vec<u8>>, R>>>> {
-        let mut buf = Vec::with_capacity(4096);
-        (&mut data).take(4096).read_to_end(&mut buf)?;
-        match self.get_file_type(&buf) {
-            Some(item_type) => match self.plugins.get(&item_type) {
+    ) -> io::Result<Result<PreProcessedInput<SniffedData<R>>, Option<Output>>> {
+        let mut buf = Vec::with_capacity(SNIFF_SIZE as usize);
+        (&mut data).take(SNIFF_SIZE).read_to_end(&mut buf)?;
+        let empty_type = EMPTY_TYPE.to_string();
+        let mut type_hint = type_hint;
+        if buf.is_empty() {
+            match self.empty_inputs {
+                EmptyInputs::Detect => {}
+                EmptyInputs::Skip => {
+                    debug!("{}: Skipping empty input {:?}", task_id, item_path);
+                    return Ok(Err(None));
+                }
+                EmptyInputs::Record => {
+                    info!("{}: Skipping empty input {:?}", task_id, item_path);
+                    let data = OutputData::Skipped(SkipReason::Empty);
+                    return Ok(Err(Some(Output::new(
+                        task_id, item_path, origin, "", "", data,
+                    ))));
+                }
+                EmptyInputs::Route => type_hint = Some(&empty_type),
+            }
+        }
+        let tail = match file_path {
+            Some(path) if self.read_tail && type_hint.is_none() => read_tail(path)?,
+            _ => Vec::new(),
+        };
+        // The deep scan appends to buf, which is chained back in front of the data
+        let head_len = buf.len();
+        let detection = match type_hint {
+            Some(t) => Some((t.clone(), None, Vec::new())),
+            None => match (self.get_file_type(&buf, &tail), file_path) {
+                (None, Some(path)) if !self.deep_scan.is_empty() => {
+                    debug!("{}: Deep scan of {:?}", task_id, path);
+                    self.deep_scan(&mut data, &mut buf)?
+                }
+                (detection, _) => detection,
+            }
+            .map(|x| (x.file_type, Some(x.rule), x.tags)),
+        };
+        match detection {
+            Some((item_type, rule, tags)) => match self.plugins.get(&item_type) {
                 Some(plugin) => {
-                    let pplugin = plugin.prep(file_path)?;
+                    let pplugin = plugin.prep(file_path, &buf, &self.working_dir)?;
                     debug!("{}: Prepped plugin: {:?}", task_id, pplugin);
                     info!(
                         "{}: Processing {:?} type: {} with plugin: {}",
                         task_id, item_path, item_type, pplugin.plugin_name
                     );
-                    Ok(Some(PreProcessedInput {
+                    Ok(Ok(PreProcessedInput {
                         task_id,
                         item_path,
+                        origin,
                         item_type,
+                        detector_rule: rule,
+                        binary: is_binary(&buf[..head_len]),
+                        mtime: None,
+                        tags,
                         plugin: pplugin,
                         data: Cursor::new(buf).chain(data),
                     }))
@@ -80,7 +459,12 @@ impl PreProcessor {
                         "{}: File type for {:?} not included in config: {}",
                         task_id, item_path, item_type
                     );
-                    Ok(None)
+                    let data = OutputData::Skipped(SkipReason::NoPlugin);
+                    let mut output =
+                        Output::new(task_id, item_path, origin, item_type, "".into(), data);
+                    output.detector_rule = rule;
+                    output.tags = tags;
+                    Ok(Err(Some(output)))
                 }
             },
             None => {
@@ -88,28 +472,73 @@ impl PreProcessor {
                     "{}: File type for {:?} was not determined",
                     task_id, item_path
                 );
-                Ok(None)
+                let data = OutputData::Skipped(SkipReason::UnknownType);
+                Ok(Err(Some(Output::new(
+                    task_id, item_path, origin, "", "", data,
+                ))))
             }
         }
     }
 
-    fn get_file_type(&self, head: &[u8]) -> Option<FileType> {
-        let head_str = String::from_utf8_lossy(head);
-        for (t, r) in self.compiled.iter() {
-            if r.is_match(&head_str) {
-                return Some(t.clone());
-            }
-        }
-        let mut head_hex = String::with_capacity(head.len() * 2);
-        for byte in head {
-            write!(head_hex, "{:02X}", byte).unwrap();
-        }
-        for (t, r) in self.compiled_hex.iter() {
-            if r.is_match(&head_hex) {
-                return Some(t.clone());
+    fn get_file_type(&self, head: &[u8], tail: &[u8]) -> Option<Detection> {
+        self.detector.detect(head, tail)
+    }
+
+    // Reads windows of the data into buf that double in size until a type with a deep
+    // scan matches, each type is only matched within its cap
+    fn deep_scan<R: Read>(&self, data: &mut R, buf: &mut Vec<u8>) -> io::Result<Option<Detection>> {
+        let max = self.deep_scan.iter().map(|x| x.0).max().unwrap_or(0);
+        let mut window = SNIFF_SIZE;
+        // A window that isn't full means the whole input was scanned
+        while window < max && buf.len() as u64 == window {
+            let scanned = window;
+            window = (window * 2).min(max);
+            data.by_ref().take(window - scanned).read_to_end(buf)?;
+            debug!("Deep scan window of {} bytes", buf.len());
+            for (cap, detector) in self.deep_scan.iter().filter(|x| x.0 > scanned) {
+                let len = buf.len().min(*cap as usize);
+                if let Some(detection) = detector.detect(&buf[..len], &[]) {
+                    return Ok(Some(detection));
+                }
             }
         }
-        None
+        Ok(None)
+    }
+}
+
+// Like perl's -B: a NUL byte or over a third of control characters other than whitespace
+fn is_binary(head: &[u8]) -> bool {
+    let control = head
+        .iter()
+        .filter(|x| (**x < 0x20 && !b"\t\n\r\x0c".contains(*x)) || **x == 0x7f)
+        .count();
+    head.contains(&0) || control * 3 > head.len()
+}
+
+fn read_tail(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(SNIFF_SIZE)))?;
+    let mut buf = Vec::with_capacity(SNIFF_SIZE as usize);
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+// Reads the head on another thread, so a stalled writer holds up the caller for at most
+// the timeout. After a timeout the reader is dropped once the read returns.
+pub fn read_head<R: Read + Send + 'static>(
+    mut data: R,
+    timeout: Duration,
+) -> io::Result<Option<(Vec<u8>, R)>> {
+    let (sender, receiver) = bounded(1);
+    thread::spawn(move || {
+        let mut buf = Vec::with_capacity(SNIFF_SIZE as usize);
+        let result = (&mut data).take(SNIFF_SIZE).read_to_end(&mut buf);
+        sender.send(result.map(|_| (buf, data))).ok();
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result.map(Some),
+        Err(_) => Ok(None),
     }
 }
 
@@ -117,8 +546,6 @@ impl PreProcessor {
 mod tests {
     use super::*;
 
-    use crate::plugin::{Header, Plugin, Settings};
-
     fn empty_plugin() -> Plugin {
         Plugin {
             name: "".into(),
@@ -127,26 +554,62 @@ mod tests {
             input: None,
             output: None,
             unpacker: None,
+            ..Default::default()
+        }
+    }
+
+    fn header(regex: &str) -> Header {
+        Header {
+            regex: regex.into(),
+            hex: None,
+            offset: None,
+            case_insensitive: None,
+            tags: Vec::new(),
+        }
+    }
+
+    // A type matched by a single header, the rest of the fields can be set with `..`
+    fn settings(regex: &str) -> Settings {
+        with_headers(vec![header(regex)])
+    }
+
+    fn with_headers(headers: Vec<Header>) -> Settings {
+        Settings {
+            headers,
+            footers: Vec::new(),
+            plugin: empty_plugin(),
+            priority: None,
+            deep_scan: None,
         }
     }
 
+    fn file_type(pp: &PreProcessor, head: &[u8]) -> Option<FileType> {
+        pp.get_file_type(head, &[]).map(|x| x.file_type)
+    }
+
     #[test]
     fn test_get_file_type() {
         let conf = vec![(
             "foo".into(),
             Settings {
-                header: Header {
+                headers: vec![Header {
                     regex: "^.FOO".into(),
                     hex: None,
-                },
+                    offset: None,
+                    case_insensitive: None,
+                    tags: Vec::new(),
+                }],
                 plugin: empty_plugin(),
+                footers: Vec::new(),
+                priority: None,
+                deep_scan: None,
             },
         )]
         .into_iter()
         .collect();
-        let pp = PreProcessor::new(&conf);
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
         assert_eq!(
-            pp.get_file_type(&[0x8b, 0x46, 0x4f, 0x4f, 0x8b]),
+            file_type(&pp, &[0x8b, 0x46, 0x4f, 0x4f, 0x8b]),
             Some("foo".into())
         );
     }
@@ -156,19 +619,358 @@ mod tests {
         let conf = vec![(
             "bar".into(),
             Settings {
-                header: Header {
+                headers: vec![Header {
                     regex: "^8B 00 .. 4f4F$".into(),
                     hex: Some(true),
-                },
+                    offset: None,
+                    case_insensitive: None,
+                    tags: Vec::new(),
+                }],
                 plugin: empty_plugin(),
+                footers: Vec::new(),
+                priority: None,
+                deep_scan: None,
             },
         )]
         .into_iter()
         .collect();
-        let pp = PreProcessor::new(&conf);
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
         assert_eq!(
-            pp.get_file_type(&[0x8b, 0x00, 0x46, 0x4f, 0x4f]),
+            file_type(&pp, &[0x8b, 0x00, 0x46, 0x4f, 0x4f]),
             Some("bar".into())
         );
     }
+
+    #[test]
+    fn test_get_file_type_offset() {
+        let foo = Header {
+            offset: Some(2),
+            ..header("^FOO")
+        };
+        let bar = Header {
+            hex: Some(true),
+            offset: Some(3),
+            ..header("^4F 4F")
+        };
+        let conf = vec![
+            ("foo".into(), with_headers(vec![foo])),
+            ("bar".into(), with_headers(vec![bar])),
+        ]
+        .into_iter()
+        .collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        assert_eq!(file_type(&pp, b"..FOO"), Some("foo".into()));
+        assert_eq!(file_type(&pp, b"...OO"), Some("bar".into()));
+        assert_eq!(file_type(&pp, b"FOO"), None);
+        assert_eq!(file_type(&pp, b"."), None);
+    }
+
+    #[test]
+    fn test_get_file_type_tags() {
+        let conf: Config = serde_yaml::from_str(
+            "upx: {header: {regex: UPX!, tags: [packed]}, plugin: {name: foo, path: foo}}",
+        )
+        .unwrap();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        let detection = pp.get_file_type(b"..UPX!..", &[]).unwrap();
+        assert_eq!(vec!["packed".to_string()], detection.tags);
+    }
+
+    #[test]
+    fn test_get_file_type_priority() {
+        let elf = Settings {
+            priority: Some(1),
+            ..with_headers(vec![Header {
+                hex: Some(true),
+                ..header("^7F 45 4C 46")
+            }])
+        };
+        let conf = vec![
+            ("text".into(), settings("^.")),
+            ("script".into(), settings("^#!")),
+            ("script/sh".into(), settings("^#!/bin/sh")),
+            ("elf".into(), elf),
+        ]
+        .into_iter()
+        .collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        assert_eq!(file_type(&pp, b"foo"), Some("text".into()));
+        assert_eq!(file_type(&pp, b"#!/bin/sh\n"), Some("script/sh".into()));
+        assert_eq!(file_type(&pp, b"#!/bin/bash\n"), Some("script".into()));
+        assert_eq!(file_type(&pp, b"\x7fELF"), Some("elf".into()));
+        assert_eq!(
+            pp.get_file_type(b"#!/bin/sh\n", &[]),
+            Some(Detection {
+                file_type: "script/sh".into(),
+                rule: "^#!/bin/sh".into(),
+                tags: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_file_type_case_insensitive() {
+        let xml = Header {
+            case_insensitive: Some(true),
+            ..header("^<\\?XML")
+        };
+        let conf = vec![("xml".into(), with_headers(vec![xml]))]
+            .into_iter()
+            .collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        assert_eq!(
+            file_type(&pp, b"<?xml version=\"1.0\"?>"),
+            Some("xml".into())
+        );
+    }
+
+    #[test]
+    fn test_get_file_type_multiple_headers() {
+        let hex = Header {
+            hex: Some(true),
+            ..header("^FF D8 FF E0")
+        };
+        let conf = vec![("jpeg".into(), with_headers(vec![hex, header("^JFIF")]))]
+            .into_iter()
+            .collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        assert_eq!(file_type(&pp, b"\xff\xd8\xff\xe0"), Some("jpeg".into()));
+        assert_eq!(file_type(&pp, b"JFIF"), Some("jpeg".into()));
+        assert_eq!(file_type(&pp, b"\xff\xd8"), None);
+    }
+
+    #[test]
+    fn test_detection_order() {
+        let png = Header {
+            hex: Some(true),
+            ..header("^89 50 4E 47")
+        };
+        let conf = vec![
+            ("text".into(), settings("^.")),
+            ("png".into(), with_headers(vec![png])),
+        ]
+        .into_iter()
+        .collect();
+        let mut detector = RegexDetector::new(&conf);
+        let mut detect = |order, head: &[u8]| {
+            detector.order = order;
+            detector.detect(head, &[]).map(|x| x.file_type)
+        };
+        assert_eq!(Some("png".into()), detect(DetectionOrder::best, b"\x89PNG"));
+        assert_eq!(
+            Some("text".into()),
+            detect(DetectionOrder::text_first, b"\x89PNG")
+        );
+        assert_eq!(
+            Some("png".into()),
+            detect(DetectionOrder::hex_first, b"\x89PNG")
+        );
+        assert_eq!(
+            Some("text".into()),
+            detect(DetectionOrder::hex_first, b"foo")
+        );
+        // The hex string of the previous input isn't matched again
+        assert_eq!(
+            Some("png".into()),
+            detect(DetectionOrder::hex_first, b"\x89PNG")
+        );
+        assert_eq!(None, detect(DetectionOrder::hex_first, b""));
+    }
+
+    #[test]
+    fn test_chain_detector() {
+        let detector = |t: &str, regex: &str| -> Box<dyn Detector> {
+            let conf = vec![(t.into(), settings(regex))].into_iter().collect();
+            Box::new(RegexDetector::new(&conf))
+        };
+        let chain = ChainDetector(vec![detector("foo", "^FOO"), detector("text", "^.")]);
+        assert_eq!(
+            chain.detect(b"FOO", &[]).map(|x| x.file_type),
+            Some("foo".into())
+        );
+        assert_eq!(
+            chain.detect(b"BAR", &[]).map(|x| x.file_type),
+            Some("text".into())
+        );
+        assert_eq!(chain.detect(b"", &[]).map(|x| x.file_type), None);
+    }
+
+    #[test]
+    fn test_disabled_plugin() {
+        let disabled = Settings {
+            plugin: Plugin {
+                enabled: Some(false),
+                ..empty_plugin()
+            },
+            ..settings("^FOO")
+        };
+        let conf = vec![("foo".into(), disabled), ("text".into(), settings("^."))]
+            .into_iter()
+            .collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        assert_eq!(file_type(&pp, b"FOO"), Some("text".into()));
+        assert!(!pp.plugins.contains_key("foo"));
+        assert!(pp.plugins.contains_key("text"));
+    }
+
+    #[test]
+    fn test_pre_process_type_hint() {
+        let conf = vec![("foo".into(), settings("^FOO"))].into_iter().collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        let pre_process = |hint| {
+            pp.pre_process(
+                TaskId::new(0),
+                "".into(),
+                "".into(),
+                None,
+                hint,
+                &b"BAR"[..],
+            )
+            .unwrap()
+            .map_err(|x| x.map(|x| x.data))
+        };
+        assert!(matches!(
+            pre_process(None).err(),
+            Some(Some(OutputData::Skipped(SkipReason::UnknownType)))
+        ));
+        let ppi = pre_process(Some(&"foo".into())).unwrap();
+        assert_eq!("foo", ppi.item_type);
+        assert_eq!(None, ppi.detector_rule);
+    }
+
+    #[test]
+    fn test_pre_process_empty() {
+        let conf = vec![("empty".into(), with_headers(Vec::new()))]
+            .into_iter()
+            .collect();
+        let mut pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        let mut pre_process = |policy| {
+            pp.empty_inputs = policy;
+            pp.pre_process(TaskId::new(0), "".into(), "".into(), None, None, &b""[..])
+                .unwrap()
+                .map(|x| x.item_type)
+                .map_err(|x| x.map(|x| x.data))
+        };
+        assert!(matches!(
+            pre_process(EmptyInputs::Detect),
+            Err(Some(OutputData::Skipped(SkipReason::UnknownType)))
+        ));
+        assert!(matches!(pre_process(EmptyInputs::Skip), Err(None)));
+        assert!(matches!(
+            pre_process(EmptyInputs::Record),
+            Err(Some(OutputData::Skipped(SkipReason::Empty)))
+        ));
+        assert_eq!(
+            Some(EMPTY_TYPE.into()),
+            pre_process(EmptyInputs::Route).ok()
+        );
+        assert_eq!(Ok(EmptyInputs::Route), "route".parse());
+        assert!("none".parse::<EmptyInputs>().is_err());
+    }
+
+    #[test]
+    fn test_pre_process_deep_scan() {
+        let marker = |priority, deep_scan| Settings {
+            priority: Some(priority),
+            deep_scan: Some(deep_scan),
+            ..settings("MARKER")
+        };
+        let conf = vec![
+            ("far".into(), marker(0, 65536)),
+            ("near".into(), marker(1, 8192)),
+        ]
+        .into_iter()
+        .collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        let path = crate::plugin::gen_path(&std::env::temp_dir());
+        let pre_process = |file_path, at| {
+            let mut data = vec![b'.'; at];
+            data.extend_from_slice(b"MARKER\n");
+            std::fs::write(&path, &data).unwrap();
+            pp.pre_process(
+                TaskId::new(0),
+                "".into(),
+                "".into(),
+                file_path,
+                None,
+                File::open(&path).unwrap(),
+            )
+            .unwrap()
+            .ok()
+            .map(|mut x| {
+                // The scanned data is still passed on in full
+                let mut buf = Vec::new();
+                x.data.read_to_end(&mut buf).unwrap();
+                assert_eq!(data, buf);
+                x.item_type
+            })
+        };
+        let near = pre_process(Some(&path), 5000);
+        let far = pre_process(Some(&path), 10000);
+        let beyond = pre_process(Some(&path), 70000);
+        let from_stream = pre_process(None, 5000);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some("near".into()), near);
+        assert_eq!(Some("far".into()), far);
+        assert_eq!(None, beyond);
+        assert_eq!(None, from_stream);
+    }
+
+    #[test]
+    fn test_read_head() {
+        struct Stalled;
+        impl Read for Stalled {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(200));
+                Ok(0)
+            }
+        }
+        let timeout = Duration::from_millis(20);
+        let (head, _) = read_head(Cursor::new(b"foo"), timeout).unwrap().unwrap();
+        assert_eq!(b"foo", head.as_slice());
+        assert!(read_head(Stalled, timeout).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_is_binary() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary("caf\u{e9}\r\n\tbar\n".as_bytes()));
+        assert!(is_binary(b"foo\0bar"));
+        assert!(is_binary(b"\x01\x02\x7fab"));
+    }
+
+    #[test]
+    fn test_pre_process_footer() {
+        let conf = vec![(
+            "pdf".into(),
+            Settings {
+                footers: vec![header("%%EOF\\s*$")],
+                ..with_headers(Vec::new())
+            },
+        )]
+        .into_iter()
+        .collect();
+        let pp = PreProcessor::new(&conf, DetectionOrder::best, "".into());
+        let path = crate::plugin::gen_path(&std::env::temp_dir());
+        let mut data = vec![b'.'; SNIFF_SIZE as usize * 2];
+        data.extend_from_slice(b"%%EOF\n");
+        std::fs::write(&path, &data).unwrap();
+        let pre_process = |file_path| {
+            pp.pre_process(
+                TaskId::new(0),
+                "".into(),
+                "".into(),
+                file_path,
+                None,
+                File::open(&path).unwrap(),
+            )
+            .unwrap()
+            .ok()
+        };
+        let from_file = pre_process(Some(&path)).map(|x| x.item_type);
+        let from_stream = pre_process(None).map(|x| x.item_type);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some("pdf".into()), from_file);
+        assert_eq!(None, from_stream);
+    }
 }
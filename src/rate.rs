@@ -0,0 +1,110 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Token bucket holding a single token, so there is no burst above the rate
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> RateLimiter {
+        assert!(per_second > 0, "Rate must be above 0");
+        RateLimiter {
+            rate: per_second as f64,
+            bucket: Mutex::new((1.0, Instant::now())),
+        }
+    }
+
+    // Blocks until a token is available, or returns false if there was none within the
+    // timeout
+    pub fn acquire(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|x| Instant::now() + x);
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let (tokens, last) = *bucket;
+                let tokens = (tokens + (now - last).as_secs_f64() * self.rate).min(1.0);
+                if tokens >= 1.0 {
+                    *bucket = (tokens - 1.0, now);
+                    return true;
+                }
+                *bucket = (tokens, now);
+                Duration::from_secs_f64((1.0 - tokens) / self.rate)
+            };
+            let wait = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return false;
+                    }
+                    wait.min(left)
+                }
+                None => wait,
+            };
+            thread::sleep(wait);
+        }
+    }
+}
+
+// What a task does when it gets no token within the throttle wait
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ThrottleAction {
+    // It keeps waiting for its turn
+    Wait,
+    // It's dropped without running its plugin
+    Drop,
+}
+
+impl FromStr for ThrottleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ThrottleAction, String> {
+        match s {
+            "wait" => Ok(ThrottleAction::Wait),
+            "drop" => Ok(ThrottleAction::Drop),
+            _ => Err(format!("Invalid throttle action: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = Arc::new(RateLimiter::new(40));
+        let start = Instant::now();
+        let threads = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        assert!(limiter.acquire(None));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // The first token is available right away
+        assert!(start.elapsed() >= Duration::from_millis(19 * 1000 / 40));
+    }
+
+    #[test]
+    fn test_rate_limiter_timeout() {
+        let limiter = RateLimiter::new(1);
+        assert!(limiter.acquire(Some(Duration::ZERO)));
+        let start = Instant::now();
+        assert!(!limiter.acquire(Some(Duration::from_millis(50))));
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(50) && waited < Duration::from_millis(500));
+    }
+}
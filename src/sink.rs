@@ -0,0 +1,581 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, warn};
+
+pub static BATCH_SIZE: usize = 1000;
+
+pub static BATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+static RETRIES: u32 = 3;
+
+static RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// For connecting and for each read and write, so an endpoint that stops answering can't
+// hold up the output threads
+static TIMEOUT: Duration = Duration::from_secs(30);
+
+// Batches are taken from the buffer under the lock and posted outside it, so a slow
+// endpoint only holds up the thread posting
+#[derive(Clone)]
+pub struct HttpSink {
+    url: Arc<Url>,
+    batch: Arc<Mutex<Batch>>,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> io::Result<HttpSink> {
+        let sink = HttpSink {
+            url: Arc::new(Url::parse(url)?),
+            batch: Arc::new(Mutex::new(Batch {
+                buf: Vec::new(),
+                records: 0,
+                last_flush: Instant::now(),
+            })),
+        };
+        let (url, batch) = (sink.url.clone(), Arc::downgrade(&sink.batch));
+        thread::spawn(move || flush_periodically(&url, batch));
+        Ok(sink)
+    }
+
+    // Records in batches that failed to post
+    pub fn dropped(&self) -> usize {
+        self.url.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Write for HttpSink {
+    // The bytes are accepted once buffered, a batch that fails to post is dropped
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let batch = {
+            let mut batch = self.batch.lock().unwrap();
+            batch.buf.extend_from_slice(buf);
+            batch.records += buf.iter().filter(|x| **x == b'\n').count();
+            if batch.records >= BATCH_SIZE {
+                batch.take()
+            } else {
+                None
+            }
+        };
+        if let Some((body, records)) = batch {
+            self.url.post_or_drop(&body, records).ok();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let batch = self.batch.lock().unwrap().take();
+        match batch {
+            Some((body, records)) => self.url.post_or_drop(&body, records),
+            None => Ok(()),
+        }
+    }
+}
+
+fn flush_periodically(url: &Url, batch: Weak<Mutex<Batch>>) {
+    loop {
+        thread::sleep(BATCH_INTERVAL / 5);
+        let taken = match batch.upgrade() {
+            Some(batch) => {
+                let mut batch = batch.lock().unwrap();
+                if batch.last_flush.elapsed() >= BATCH_INTERVAL {
+                    batch.take()
+                } else {
+                    None
+                }
+            }
+            None => break,
+        };
+        if let Some((body, records)) = taken {
+            url.post_or_drop(&body, records).ok();
+        }
+    }
+}
+
+// Compresses the records written by all clones into one gzip stream. The stream is
+// finished by `finish`, or when the last clone is dropped.
+pub struct GzipSink<W: Write>(Arc<Mutex<GzipStream<W>>>);
+
+impl<W: Write> GzipSink<W> {
+    pub fn new(inner: W) -> GzipSink<W> {
+        let encoder = GzEncoder::new(inner, Compression::default());
+        GzipSink(Arc::new(Mutex::new(GzipStream(Some(encoder)))))
+    }
+
+    // Writes the end of the stream, the clones can't write to it after this
+    pub fn finish(&self) -> io::Result<()> {
+        match self.0.lock().unwrap().0.take() {
+            Some(encoder) => encoder.finish()?.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+struct GzipStream<W: Write>(Option<GzEncoder<W>>);
+
+impl<W: Write> Drop for GzipStream<W> {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.0.take() {
+            if let Err(err) = encoder.finish().and_then(|mut x| x.flush()) {
+                error!("Failed to finish the gzip stream: {}", err);
+            }
+        }
+    }
+}
+
+impl<W: Write> Clone for GzipSink<W> {
+    fn clone(&self) -> GzipSink<W> {
+        GzipSink(self.0.clone())
+    }
+}
+
+impl<W: Write> Write for GzipSink<W> {
+    // Records are written whole, so they aren't interleaved with those of other clones
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.lock().unwrap().0.as_mut() {
+            Some(encoder) => encoder.write_all(buf).map(|_| buf.len()),
+            None => Err(Error::other("Write after the gzip stream was finished")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.lock().unwrap().0.as_mut() {
+            Some(encoder) => encoder.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// A sink that can be cloned as a trait object, so sinks of different types can be teed
+pub trait CloneSink: Write + Send + Sync {
+    fn clone_sink(&self) -> Box<dyn CloneSink>;
+}
+
+impl<W: Write + Clone + Send + Sync + 'static> CloneSink for W {
+    fn clone_sink(&self) -> Box<dyn CloneSink> {
+        Box::new(self.clone())
+    }
+}
+
+// What a TeeSink does when one of its sinks fails
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TeeErrors {
+    // The write fails
+    FailFast,
+    // The error is logged and the other sinks are still written, the write only fails
+    // if all of them did
+    BestEffort,
+}
+
+impl FromStr for TeeErrors {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TeeErrors, String> {
+        match s {
+            "fail-fast" => Ok(TeeErrors::FailFast),
+            "best-effort" => Ok(TeeErrors::BestEffort),
+            _ => Err(format!("Invalid output error policy: {}", s)),
+        }
+    }
+}
+
+// Writes the records to each of its sinks. With best-effort a sink is skipped by all the
+// clones after it failed once.
+pub struct TeeSink {
+    sinks: Vec<(Box<dyn CloneSink>, Arc<AtomicBool>)>,
+    errors: TeeErrors,
+}
+
+impl TeeSink {
+    pub fn new(errors: TeeErrors) -> TeeSink {
+        TeeSink {
+            sinks: Vec::new(),
+            errors,
+        }
+    }
+
+    pub fn add<W: CloneSink + 'static>(&mut self, sink: W) {
+        self.sinks
+            .push((Box::new(sink), Arc::new(AtomicBool::new(false))));
+    }
+
+    fn each<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut dyn CloneSink) -> io::Result<()>,
+    {
+        for (idx, (sink, dead)) in self.sinks.iter_mut().enumerate() {
+            if dead.load(Ordering::Relaxed) {
+                continue;
+            }
+            if let Err(err) = f(sink.as_mut()) {
+                match self.errors {
+                    TeeErrors::FailFast => return Err(err),
+                    TeeErrors::BestEffort => {
+                        if !dead.swap(true, Ordering::Relaxed) {
+                            warn!(
+                                "Failed to write to output {}, disabling it: {}",
+                                idx + 1,
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        if !self.sinks.is_empty() && self.sinks.iter().all(|x| x.1.load(Ordering::Relaxed)) {
+            return Err(Error::other("Failed to write to all outputs"));
+        }
+        Ok(())
+    }
+}
+
+impl Clone for TeeSink {
+    fn clone(&self) -> TeeSink {
+        TeeSink {
+            sinks: self
+                .sinks
+                .iter()
+                .map(|(sink, dead)| (sink.clone_sink(), dead.clone()))
+                .collect(),
+            errors: self.errors,
+        }
+    }
+}
+
+impl Write for TeeSink {
+    // Records are written whole to each sink
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.each(|x| x.write_all(buf)).map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.each(|x| x.flush())
+    }
+}
+
+struct Batch {
+    buf: Vec<u8>,
+    records: usize,
+    last_flush: Instant,
+}
+
+impl Batch {
+    // Takes the complete records and their count, a partial write stays in the buffer
+    fn take(&mut self) -> Option<(Vec<u8>, usize)> {
+        self.last_flush = Instant::now();
+        let len = self.buf.iter().rposition(|x| *x == b'\n')? + 1;
+        let rest = self.buf.split_off(len);
+        Some((
+            mem::replace(&mut self.buf, rest),
+            mem::take(&mut self.records),
+        ))
+    }
+}
+
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+    timeout: Duration,
+    dropped: AtomicUsize,
+}
+
+impl Url {
+    fn parse(url: &str) -> io::Result<Url> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid URL {}", url));
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Url {
+            host: host.into(),
+            port,
+            path: path.into(),
+            timeout: TIMEOUT,
+            dropped: AtomicUsize::new(0),
+        })
+    }
+
+    // Counts the records of a batch that fails to post as dropped
+    fn post_or_drop(&self, body: &[u8], records: usize) -> io::Result<()> {
+        let result = self.post_batch(body, records);
+        if let Err(err) = &result {
+            self.dropped.fetch_add(records, Ordering::Relaxed);
+            error!("Dropped {} records, failed to post them: {}", records, err);
+        }
+        result
+    }
+
+    // Retries server errors and failed connections, the caller drops a batch that fails
+    fn post_batch(&self, body: &[u8], records: usize) -> io::Result<()> {
+        let mut attempt = 0;
+        loop {
+            match self.post(body) {
+                Ok(status) if status < 300 => break,
+                Ok(status) if status < 500 || attempt >= RETRIES => {
+                    return Err(Error::other(format!(
+                        "HTTP status {} from {}",
+                        status, self
+                    )))
+                }
+                Err(err) if attempt >= RETRIES => return Err(err),
+                Ok(status) => warn!("HTTP status {} from {}, retrying", status, self),
+                Err(err) => warn!("Failed to post to {}, retrying: {}", self, err),
+            }
+            thread::sleep(RETRY_DELAY * 2u32.pow(attempt));
+            attempt += 1;
+        }
+        debug!("Posted {} records to {}", records, self);
+        Ok(())
+    }
+
+    fn post(&self, body: &[u8]) -> io::Result<u16> {
+        let mut stream = self.connect()?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/x-ndjson\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            self.port,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid HTTP response: {}", status_line.trim()),
+                )
+            })
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(ErrorKind::NotFound, format!("No address for {}", self.host))
+        }))
+    }
+}
+
+impl Display for Url {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+        let mut reader = BufReader::new(stream);
+        let mut len = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            if let Some(x) = line.strip_prefix("Content-Length: ") {
+                len = x.trim().parse().unwrap();
+            }
+            line.clear();
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn test_http_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for (idx, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = stream.unwrap();
+                bodies.push(read_request(&mut stream));
+                let status = if idx == 0 { 503 } else { 200 };
+                write!(stream, "HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+            bodies
+        });
+        let mut sink = HttpSink::new(&format!("http://127.0.0.1:{}/ingest", port)).unwrap();
+        let mut clone = sink.clone();
+        sink.write_all(b"{\"a\":1}\n").unwrap();
+        clone.write_all(b"{\"b\":2}\n{\"c\"").unwrap();
+        sink.flush().unwrap();
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(b"{\"a\":1}\n{\"b\":2}\n", bodies[1].as_slice());
+        assert_eq!(b"{\"c\"", sink.batch.lock().unwrap().buf.as_slice());
+    }
+
+    // A rejected batch is dropped, not posted again with the next one
+    #[test]
+    fn test_http_sink_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for (idx, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = stream.unwrap();
+                bodies.push(read_request(&mut stream));
+                let status = if idx == 0 { 400 } else { 200 };
+                write!(stream, "HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+            bodies
+        });
+        let mut sink = HttpSink::new(&format!("http://127.0.0.1:{}/ingest", port)).unwrap();
+        sink.write_all(b"{\"a\":1}\n").unwrap();
+        assert!(sink.flush().is_err());
+        assert_eq!(1, sink.dropped());
+        assert!(sink.batch.lock().unwrap().buf.is_empty());
+        sink.write_all(b"{\"b\":2}\n").unwrap();
+        sink.flush().unwrap();
+        let bodies = server.join().unwrap();
+        assert_eq!(b"{\"a\":1}\n", bodies[0].as_slice());
+        assert_eq!(b"{\"b\":2}\n", bodies[1].as_slice());
+    }
+
+    // Connected, but never answers
+    #[test]
+    fn test_url_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut url = Url::parse(&format!("http://127.0.0.1:{}/ingest", port)).unwrap();
+        url.timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        assert!(url.post(b"{}\n").is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_gzip_sink() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut sink = GzipSink::new(buf.clone());
+        let mut clone = sink.clone();
+        sink.write_all(b"{\"a\":1}\n").unwrap();
+        clone.write_all(b"{\"b\":2}\n").unwrap();
+        sink.flush().unwrap();
+        assert!(!buf.0.lock().unwrap().is_empty());
+        clone.write_all(b"{}\n").unwrap();
+        clone.flush().unwrap();
+        sink.finish().unwrap();
+        assert!(clone.write_all(b"{}\n").is_err());
+        let compressed = buf.0.lock().unwrap().clone();
+        let mut records = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut records)
+            .unwrap();
+        assert_eq!("{\"a\":1}\n{\"b\":2}\n{}\n", records);
+    }
+
+    // Counts the writes it failed
+    #[derive(Clone, Default)]
+    struct FailingSink(Arc<AtomicUsize>);
+
+    impl Write for FailingSink {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Err(Error::other("failed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tee_sink() {
+        let first = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let second = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut sink = TeeSink::new(TeeErrors::FailFast);
+        sink.add(first.clone());
+        sink.add(second.clone());
+        sink.clone().write_all(b"{\"a\":1}\n").unwrap();
+        sink.write_all(b"{\"b\":2}\n").unwrap();
+        assert_eq!(
+            b"{\"a\":1}\n{\"b\":2}\n",
+            first.0.lock().unwrap().as_slice()
+        );
+        assert_eq!(*first.0.lock().unwrap(), *second.0.lock().unwrap());
+
+        let mut sink = TeeSink::new(TeeErrors::FailFast);
+        sink.add(FailingSink::default());
+        sink.add(first.clone());
+        assert!(sink.write_all(b"{}\n").is_err());
+        let failing = FailingSink::default();
+        let mut sink = TeeSink::new(TeeErrors::BestEffort);
+        sink.add(failing.clone());
+        sink.add(first.clone());
+        sink.write_all(b"{}\n").unwrap();
+        sink.clone().write_all(b"{}\n").unwrap();
+        assert!(first.0.lock().unwrap().ends_with(b"}\n{}\n{}\n"));
+        // Disabled after its first failure, in the clones too
+        assert_eq!(1, failing.0.load(Ordering::Relaxed));
+        let mut sink = TeeSink::new(TeeErrors::BestEffort);
+        sink.add(FailingSink::default());
+        assert!(sink.write_all(b"{}\n").is_err());
+        assert!(sink.write_all(b"{}\n").is_err());
+        assert_eq!(Ok(TeeErrors::BestEffort), "best-effort".parse());
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = Url::parse("http://localhost:8080/foo/bar").unwrap();
+        assert_eq!("localhost", url.host);
+        assert_eq!(8080, url.port);
+        assert_eq!("/foo/bar", url.path);
+        let url = Url::parse("http://localhost").unwrap();
+        assert_eq!(80, url.port);
+        assert_eq!("/", url.path);
+        assert!(Url::parse("https://localhost").is_err());
+    }
+}
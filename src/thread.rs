@@ -1,57 +1,180 @@
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-use crossbeam_channel::{unbounded, Receiver, RecvError, Sender};
+use crossbeam_channel::{bounded, select, unbounded, Receiver, RecvError, Sender};
 use log::{debug, error};
 
 use crate::input::{Input, InputFactory};
-use crate::output::Output;
-use crate::plugin::Config;
+use crate::output::{Output, OutputContext, OutputData};
 use crate::pre_process::PreProcessor;
 
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static THREAD_ID: u64 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+// Numbered in order of first use, ThreadId::as_u64 is not stable
+pub fn current_id() -> u64 {
+    THREAD_ID.with(|x| *x)
+}
+
+// Counts failed inputs and outputs, once there are max of them no more inputs are handled
+#[derive(Debug, Default)]
+pub struct ErrorLimit {
+    max: Option<u64>,
+    count: AtomicU64,
+}
+
+impl ErrorLimit {
+    pub fn new(max: Option<u64>) -> ErrorLimit {
+        ErrorLimit {
+            max,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn add(&self) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        if Some(count) == self.max {
+            error!("Reached {} errors, skipping the remaining inputs", count);
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        match self.max {
+            Some(max) => self.count.load(Ordering::Relaxed) >= max,
+            None => false,
+        }
+    }
+}
+
+// Set when the run has to stop early, queued inputs are skipped and running tasks finish
+#[derive(Debug, Default)]
+pub struct Shutdown(AtomicBool);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn triggered(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 pub struct Pool<E> {
     pub factory: Arc<InputFactory>,
-    pub input_sender: Sender<Input>,
+    // Set before adding threads
+    pub errors: Arc<ErrorLimit>,
+    pub shutdown: Arc<Shutdown>,
+    // Top-level inputs, from walking the input paths
+    pub walk_sender: Sender<Input>,
+    walk_receiver: Receiver<Input>,
+    input_sender: Sender<Input>,
     input_receiver: Receiver<Input>,
-    output_sender: Sender<Output>,
+    pub output_sender: Sender<Output>,
     output_receiver: Receiver<Output>,
+    unpack_sender: Sender<Input>,
+    unpack_receiver: Receiver<Input>,
+    // Without capacity, a send only succeeds while a nested unpack thread is idle
+    nested_sender: Sender<Input>,
+    nested_receiver: Receiver<Input>,
     activity_sender: Sender<bool>,
     activity_receiver: Receiver<bool>,
     active_threads: usize,
     pre_processor: Arc<PreProcessor>,
+    context: Arc<OutputContext>,
     exit: E,
 }
 
 impl<E: Write + Clone + Send + 'static> Pool<E> {
-    pub fn new(config: Config, exit: E) -> Pool<E> {
+    pub fn new(
+        pre_processor: PreProcessor,
+        factory: InputFactory,
+        context: OutputContext,
+        exit: E,
+    ) -> Pool<E> {
+        let (walk_sender, walk_receiver) = unbounded();
         let (input_sender, input_receiver) = unbounded();
         let (output_sender, output_receiver) = unbounded();
+        let (unpack_sender, unpack_receiver) = unbounded();
+        let (nested_sender, nested_receiver) = bounded(0);
         let (activity_sender, activity_receiver) = unbounded();
         Pool {
-            factory: Arc::new(InputFactory::new()),
-            pre_processor: Arc::new(PreProcessor::new(&config)),
+            factory: Arc::new(factory),
+            errors: Arc::new(ErrorLimit::default()),
+            shutdown: Arc::new(Shutdown::default()),
+            pre_processor: Arc::new(pre_processor),
+            context: Arc::new(context),
             active_threads: 0,
+            walk_sender,
+            walk_receiver,
             input_sender,
             input_receiver,
             output_sender,
             output_receiver,
+            unpack_sender,
+            unpack_receiver,
+            nested_sender,
+            nested_receiver,
             activity_sender,
             activity_receiver,
             exit,
         }
     }
+
+    // Sending a walked input blocks while size walked inputs are queued, so the walk stays
+    // just ahead of the input threads. Set before adding threads.
+    pub fn limit_walk_queue(&mut self, size: usize) {
+        let (walk_sender, walk_receiver) = bounded(size);
+        self.walk_sender = walk_sender;
+        self.walk_receiver = walk_receiver;
+    }
+
+    fn input_handler(&self, input_receiver: &Receiver<Input>, unpacker: bool) -> InputHandler {
+        InputHandler {
+            factory: self.factory.clone(),
+            errors: self.errors.clone(),
+            shutdown: self.shutdown.clone(),
+            walk_receiver: if unpacker {
+                None
+            } else {
+                Some(self.walk_receiver.clone())
+            },
+            input_receiver: input_receiver.clone(),
+            input_sender: self.input_sender.clone(),
+            output_sender: self.output_sender.clone(),
+            unpack_sender: self.unpack_sender.clone(),
+            nested_sender: self.nested_sender.clone(),
+            activity_sender: self.activity_sender.clone(),
+            pre_processor: self.pre_processor.clone(),
+            unpacker,
+        }
+    }
+
     pub fn add_input_threads(&self, num: usize) {
         for _ in 0..num {
-            let handler = InputHandler {
-                factory: self.factory.clone(),
-                input_receiver: self.input_receiver.clone(),
-                input_sender: self.input_sender.clone(),
-                output_sender: self.output_sender.clone(),
-                activity_sender: self.activity_sender.clone(),
-                pre_processor: self.pre_processor.clone(),
-            };
+            let handler = self.input_handler(&self.input_receiver, false);
+            thread::spawn(move || handler.run());
+        }
+    }
+
+    // Threads reading the stdout of unpackers, which is streamed while the unpacker runs
+    pub fn add_unpack_threads(&self, num: usize) {
+        for _ in 0..num {
+            let handler = self.input_handler(&self.unpack_receiver, true);
+            thread::spawn(move || handler.run());
+        }
+    }
+
+    // Threads reading the stdout of unpackers nested in the stdout of another unpacker
+    pub fn add_nested_unpack_threads(&self, num: usize) {
+        for _ in 0..num {
+            let handler = self.input_handler(&self.nested_receiver, true);
             thread::spawn(move || handler.run());
         }
     }
@@ -59,16 +182,34 @@ impl<E: Write + Clone + Send + 'static> Pool<E> {
     pub fn add_output_threads(&self, num: usize) {
         for _ in 0..num {
             let mut exit = self.exit.clone();
+            let context = self.context.clone();
+            let errors = self.errors.clone();
             let receiver = self.output_receiver.clone();
             let sender = self.activity_sender.clone();
-            thread::spawn(move || run_thread(&receiver, &sender, |o| handle_output(&mut exit, o)));
+            thread::spawn(move || {
+                run_thread(
+                    || receiver.recv().unwrap(),
+                    &sender,
+                    |o| handle_output(&mut exit, &context, &errors, o),
+                )
+            });
         }
     }
 
+    pub fn queue_depth(&self) -> QueueDepth {
+        QueueDepth(vec![
+            self.walk_receiver.clone(),
+            self.input_receiver.clone(),
+            self.unpack_receiver.clone(),
+        ])
+    }
+
     pub fn join(&mut self) -> Result<(), RecvError> {
         thread::sleep(Duration::from_millis(10));
         while self.active_threads > 0
+            || !self.walk_receiver.is_empty()
             || !self.input_receiver.is_empty()
+            || !self.unpack_receiver.is_empty()
             || !self.output_receiver.is_empty()
             || !self.activity_receiver.is_empty()
         {
@@ -84,9 +225,23 @@ impl<E: Write + Clone + Send + 'static> Pool<E> {
     }
 }
 
-fn run_thread<T, F: FnMut(T)>(receiver: &Receiver<T>, activity_sender: &Sender<bool>, mut f: F) {
+// Number of inputs waiting for a thread, can be read from outside the pool
+#[derive(Clone)]
+pub struct QueueDepth(Vec<Receiver<Input>>);
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.0.iter().map(Receiver::len).sum()
+    }
+}
+
+fn run_thread<T, R, F>(mut recv: R, activity_sender: &Sender<bool>, mut f: F)
+where
+    R: FnMut() -> T,
+    F: FnMut(T),
+{
     loop {
-        let msg = receiver.recv().unwrap();
+        let msg = recv();
         activity_sender.send(true).unwrap();
         f(msg);
         activity_sender.send(false).unwrap();
@@ -96,17 +251,45 @@ fn run_thread<T, F: FnMut(T)>(receiver: &Receiver<T>, activity_sender: &Sender<b
 #[derive(Clone)]
 struct InputHandler {
     factory: Arc<InputFactory>,
+    errors: Arc<ErrorLimit>,
+    shutdown: Arc<Shutdown>,
     pre_processor: Arc<PreProcessor>,
+    // None for unpack threads
+    walk_receiver: Option<Receiver<Input>>,
     input_receiver: Receiver<Input>,
     input_sender: Sender<Input>,
     output_sender: Sender<Output>,
+    unpack_sender: Sender<Input>,
+    nested_sender: Sender<Input>,
     activity_sender: Sender<bool>,
+    // Handles the stdout of unpackers
+    unpacker: bool,
 }
 
 impl InputHandler {
+    // Nested inputs are scheduled while their parent is handled, so the origin is only
+    // finished by the last of them
     fn handle_input(&self, input: Input) {
+        let origin = input.origin.clone();
+        self.run_input(input);
+        self.factory.budget.finish(&origin);
+    }
+
+    fn run_input(&self, input: Input) {
         let task_id = input.task_id;
         let path = input.item_path.clone();
+        let origin = input.origin.clone();
+        if self.errors.exceeded() {
+            debug!(
+                "{}: Skipping input {:?} after the error limit",
+                task_id, path
+            );
+            return;
+        }
+        if self.shutdown.triggered() {
+            debug!("{}: Skipping input {:?} after shutdown", task_id, path);
+            return;
+        }
         debug!(
             "{}: START Input {:?} data: {:?}",
             input.task_id, path, input.data
@@ -120,44 +303,168 @@ impl InputHandler {
             )
             .err()
         {
-            error!("{}: FINISH Input {:?} error: {:?}", task_id, path, err)
+            error!("{}: FINISH Input {:?} error: {:?}", task_id, path, err);
+            self.errors.add();
+            let data = OutputData::Error(err.to_string());
+            let output = Output::new(task_id, path, origin, "", "", data);
+            self.output_sender.send(output).unwrap();
         } else {
             debug!("{}: FINISH Input {:?}", task_id, path);
         }
     }
 
+    // Stdout has to be read while the unpacker runs, so it can't wait behind other inputs
+    // in the input channel. Nested stdout goes to an idle nested unpack thread, or is read
+    // on this thread when there is none, as waiting for one could deadlock.
     fn schedule_input(&self, input: Input) {
-        if input.data.is_stdout() {
-	    let clone = self.clone();
-            thread::spawn(move || {
-                clone.handle_input(input);
-            });
+        if input.data.is_stdout() && self.unpacker {
+            if let Err(err) = self.nested_sender.try_send(input) {
+                self.handle_input(err.into_inner());
+            }
+        } else if input.data.is_stdout() {
+            self.unpack_sender.send(input).unwrap();
         } else {
             self.input_sender.send(input).unwrap();
         }
     }
 
+    // Nested inputs are taken first, so inputs already being unpacked finish before more
+    // walked ones are started
+    fn recv(&self) -> Input {
+        if let Ok(input) = self.input_receiver.try_recv() {
+            return input;
+        }
+        match &self.walk_receiver {
+            Some(walk_receiver) => select! {
+                recv(self.input_receiver) -> x => x.unwrap(),
+                recv(walk_receiver) -> x => x.unwrap(),
+            },
+            None => self.input_receiver.recv().unwrap(),
+        }
+    }
+
     fn run(self) {
-        run_thread(&self.input_receiver, &self.activity_sender, |x| {
-            self.handle_input(x)
-        })
+        run_thread(
+            || self.recv(),
+            &self.activity_sender,
+            |x| self.handle_input(x),
+        )
     }
 }
 
-fn handle_output<E: Write>(exit: &mut E, output: Output) {
+fn handle_output<E: Write>(
+    exit: &mut E,
+    context: &OutputContext,
+    errors: &ErrorLimit,
+    output: Output,
+) {
     let task_id = output.task_id;
     let path = output.item_path.clone();
+    let origin = output.origin.clone();
+    let item_type = output.item_type.clone();
     let plugin = output.plugin_name.clone();
     debug!(
         "{}: START Output {:?} data: {:?}",
         output.task_id, path, output.data
     );
-    if let Some(err) = output.handle(exit).err() {
+    if let Some(err) = output.handle(context, exit).err() {
         error!(
             "{}: FINISH Output {:?} plugin: {}, error: {:?}",
             task_id, path, plugin, err
-        )
+        );
+        errors.add();
+        let data = OutputData::Error(err.to_string());
+        let output = Output::new(task_id, path, origin, item_type, plugin, data);
+        if let Err(err) = output.handle(context, exit) {
+            error!("{}: Failed to write error record: {:?}", task_id, err);
+        }
     } else {
         debug!("{}: FINISH Output {:?} plugin: {}", task_id, path, plugin);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::Mutex;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use serde_json::Value;
+
+    use crate::input::InputData;
+    use crate::plugin::{gen_path, Config, DetectionOrder};
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Every level of the nested gzip stream is unpacked from the stdout of the one before
+    // it, however deep, only the threads of the pool handle them
+    #[test]
+    #[cfg(unix)]
+    fn test_nested_stdout_threads() {
+        let config: Config = serde_yaml::from_str(
+            "gz: {header: {regex: ^1F 8B, hex: true}, priority: 1, plugin: {name: gunzip, \
+             path: gzip, args: [-dc], input: stdin, output: stdout, unpacker: true}}\n\
+             text: {header: {regex: ^foo}, plugin: {name: cat, path: /bin/cat, input: stdin, \
+             output: stdout}}",
+        )
+        .unwrap();
+        let pre_processor = PreProcessor::new(&config, DetectionOrder::best, env::temp_dir());
+        let mut data = b"foo\n".to_vec();
+        for _ in 0..6 {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(&data).unwrap();
+            data = gz.finish().unwrap();
+        }
+        let path = gen_path(&env::temp_dir());
+        fs::write(&path, data).unwrap();
+        let mut context = OutputContext::new("job");
+        context.thread_ids = true;
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut pool = Pool::new(
+            pre_processor,
+            InputFactory::new(false),
+            context,
+            buf.clone(),
+        );
+        pool.add_input_threads(1);
+        pool.add_unpack_threads(1);
+        pool.add_nested_unpack_threads(1);
+        // The stderr of every level holds an output thread until its unpacker exits
+        pool.add_output_threads(16);
+        let data = InputData::File(path.clone(), false);
+        let input = pool.factory.new_input("", path.clone(), data);
+        pool.walk_sender.send(input).unwrap();
+        pool.join().unwrap();
+        fs::remove_file(path).unwrap();
+        let records = serde_json::Deserializer::from_slice(&buf.0.lock().unwrap())
+            .into_iter::<Value>()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert!(records.iter().any(|x| x.get("data") == Some(&"foo".into())));
+        // The ids of the threads creating the inputs: this one, the input thread, the unpack
+        // thread and the nested unpack thread
+        let threads = records
+            .iter()
+            .filter_map(|x| x.get("thread_id"))
+            .map(Value::to_string)
+            .collect::<HashSet<_>>();
+        assert!(threads.len() <= 4, "{:?}", threads);
+    }
+}
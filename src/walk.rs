@@ -1,24 +1,151 @@
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-pub fn walk_dir<T: Fn(PathBuf, PathBuf)>(
+// The root dir itself is never skipped as hidden, since it was asked for explicitly.
+// Without follow_links, symlinks are sent as they are instead of walking into them.
+pub fn walk_dir<T: FnMut(PathBuf, PathBuf)>(
     dir: PathBuf,
     parent_path: PathBuf,
-    send: T,
+    skip_hidden: bool,
+    follow_links: bool,
+    mut send: T,
 ) -> io::Result<()> {
     let root_depth = dir.iter().count();
     let mut dirs = vec![dir];
     while let Some(dir) = dirs.pop() {
         for entry in dir.read_dir()? {
             let path = entry?.path();
-            if path.is_dir() {
+            if skip_hidden && is_hidden(&path) {
+                continue;
+            } else if !follow_links && is_symlink(&path) {
+                send_entry(&mut send, &parent_path, root_depth, path);
+            } else if path.is_dir() {
                 dirs.push(path);
             } else if path.is_file() {
-                let mut item_path = parent_path.clone();
-                item_path.extend(path.iter().skip(root_depth));
-                send(path, item_path);
+                send_entry(&mut send, &parent_path, root_depth, path);
             }
         }
     }
     Ok(())
 }
+
+fn send_entry<T: FnMut(PathBuf, PathBuf)>(
+    send: &mut T,
+    parent_path: &Path,
+    root_depth: usize,
+    path: PathBuf,
+) {
+    let mut item_path = parent_path.to_path_buf();
+    item_path.extend(path.iter().skip(root_depth));
+    send(path, item_path);
+}
+
+// FIFOs and devices can't be read like a regular file, they may block and have no end
+#[cfg(unix)]
+pub fn is_stream(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    path.metadata()
+        .map(|x| x.file_type().is_fifo() || x.file_type().is_char_device())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub fn is_stream(_: &Path) -> bool {
+    false
+}
+
+pub fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .map(|x| x.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|x| x.to_str())
+        .map(|x| x.starts_with('.'))
+        .unwrap_or(false)
+        || has_hidden_attribute(path)
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    path.symlink_metadata()
+        .map(|x| x.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+
+    use crate::plugin::gen_path;
+
+    #[test]
+    fn test_walk_dir_skip_hidden() {
+        let root = gen_path(&env::temp_dir()).join(".root");
+        fs::create_dir_all(root.join(".hidden_dir")).unwrap();
+        fs::create_dir_all(root.join("dir")).unwrap();
+        for path in [".hidden_dir/foo", "dir/.hidden", "dir/bar", "baz"].iter() {
+            fs::write(root.join(path), b"").unwrap();
+        }
+        let walk = |skip_hidden| {
+            let found = RefCell::new(Vec::new());
+            walk_dir(root.clone(), "x".into(), skip_hidden, true, |_, ip| {
+                found.borrow_mut().push(ip)
+            })
+            .unwrap();
+            let mut found = found.into_inner();
+            found.sort();
+            found
+        };
+        let all = walk(false);
+        let visible = walk(true);
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+        assert_eq!(4, all.len());
+        assert_eq!(
+            vec![PathBuf::from("x/baz"), PathBuf::from("x/dir/bar")],
+            visible
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_walk_dir_no_follow() {
+        let root = gen_path(&env::temp_dir());
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/foo"), b"").unwrap();
+        std::os::unix::fs::symlink(root.join("dir"), root.join("link")).unwrap();
+        let walk = |follow_links| {
+            let mut found = Vec::new();
+            walk_dir(root.clone(), "".into(), false, follow_links, |_, ip| {
+                found.push(ip)
+            })
+            .unwrap();
+            found.sort();
+            found
+        };
+        let followed = walk(true);
+        let not_followed = walk(false);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            vec![PathBuf::from("dir/foo"), PathBuf::from("link/foo")],
+            followed
+        );
+        assert_eq!(
+            vec![PathBuf::from("dir/foo"), PathBuf::from("link")],
+            not_followed
+        );
+    }
+}